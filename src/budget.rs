@@ -0,0 +1,227 @@
+//! Fitting the terminal output into the context window of the model
+//!
+//! Token counts are estimated without the model's tokenizer, so budgets keep a margin.
+//! When the terminal output does not fit, the most recent lines are kept, along with
+//! older lines that look like errors.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Context windows of well-known models, by prefix of the model name. The first match wins.
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-5", 400_000),
+    ("o1-mini", 128_000),
+    ("o1-preview", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("claude", 200_000),
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini", 1_048_576),
+];
+/// Ollama's own default when num_ctx is not set
+const OLLAMA_CONTEXT_WINDOW: usize = 4_096;
+/// Unknown models, e.g. Azure deployments or those of compatible servers
+const DEFAULT_CONTEXT_WINDOW: usize = 8_192;
+
+/// Room for the answer when no maximum length is set
+const DEFAULT_OUTPUT_RESERVE: usize = 4_096;
+/// Rough cost of an attached image
+pub const IMAGE_TOKENS: usize = 1_600;
+/// Cost of the line marking dropped lines
+const MARKER_TOKENS: usize = 8;
+
+static ERROR_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\b(error|errors|failed|failure|fatal|panic|panicked|exception|traceback|denied|not found|no such file|cannot|could not|unable to|segmentation fault|undefined reference)\b",
+    )
+    .unwrap()
+});
+
+/// Estimated number of tokens of a text: about four ASCII characters per token, and a
+/// token per other character, e.g. CJK
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.bytes().filter(u8::is_ascii).count();
+    let other = text.chars().filter(|c| !c.is_ascii()).count();
+    ascii.div_ceil(4) + other
+}
+
+/// Context window of a model in tokens, guessed from its name
+pub fn context_window(provider: &str, model: &str) -> usize {
+    if provider == "ollama" {
+        return OLLAMA_CONTEXT_WINDOW;
+    }
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map_or(DEFAULT_CONTEXT_WINDOW, |(_, window)| *window)
+}
+
+/// Tokens kept free for the answer: the maximum length when set, or else a default
+/// length of at most a quarter of the window, plus the thinking budget
+pub fn output_reserve(
+    window: usize,
+    max_tokens: Option<u32>,
+    thinking_budget: Option<u32>,
+) -> usize {
+    let answer = max_tokens.map_or(DEFAULT_OUTPUT_RESERVE.min(window / 4), |t| t as usize);
+    answer + thinking_budget.unwrap_or(0) as usize
+}
+
+/// Tokens of the window left for the prompt, keeping a tenth of it for estimation errors
+pub fn prompt_budget(window: usize, output_reserve: usize) -> usize {
+    (window - window / 10).saturating_sub(output_reserve)
+}
+
+/// Text cut down to a budget
+#[derive(Debug, PartialEq)]
+pub struct Trimmed {
+    pub text: String,
+    pub kept_lines: usize,
+    pub dropped_lines: usize,
+    pub dropped_tokens: usize,   // Estimated
+    pub kept_error_lines: usize, // Older lines kept because they look like errors
+}
+
+/// Keep the most recent lines that fit the budget, and then older lines that look like
+/// errors. Dropped lines are replaced by a line telling how many there were.
+pub fn trim_lines(text: &str, budget: usize) -> Trimmed {
+    let lines: Vec<&str> = text.lines().collect();
+    let costs: Vec<usize> = lines.iter().map(|line| estimate_tokens(line) + 1).collect();
+    let total: usize = costs.iter().sum();
+    if total <= budget {
+        return Trimmed {
+            text: text.to_string(),
+            kept_lines: lines.len(),
+            dropped_lines: 0,
+            dropped_tokens: 0,
+            kept_error_lines: 0,
+        };
+    }
+
+    let mut keep = vec![false; lines.len()];
+    let mut used = MARKER_TOKENS;
+    // A quarter of the budget is left for older errors at first
+    let mut start = lines.len();
+    while start > 0 && used + costs[start - 1] <= budget - budget / 4 {
+        start -= 1;
+        keep[start] = true;
+        used += costs[start];
+    }
+
+    let mut kept_error_lines = 0;
+    for i in (0..start).rev() {
+        // An error line on its own comes with a marker before it
+        let cost = costs[i] + MARKER_TOKENS;
+        if used + cost <= budget && ERROR_LINE.is_match(lines[i]) {
+            keep[i] = true;
+            used += cost;
+            kept_error_lines += 1;
+        }
+    }
+
+    // What is left goes to more recent lines
+    while start > 0 && (keep[start - 1] || used + costs[start - 1] <= budget) {
+        start -= 1;
+        if !keep[start] {
+            keep[start] = true;
+            used += costs[start];
+        }
+    }
+
+    let mut output = Vec::new();
+    let mut dropped_run = 0;
+    let mut dropped_tokens = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if keep[i] {
+            if dropped_run > 0 {
+                output.push(format!("[... {} lines dropped ...]", dropped_run));
+                dropped_run = 0;
+            }
+            output.push(line.to_string());
+        } else {
+            dropped_run += 1;
+            dropped_tokens += costs[i];
+        }
+    }
+    if dropped_run > 0 {
+        output.push(format!("[... {} lines dropped ...]", dropped_run));
+    }
+
+    let kept_lines = keep.iter().filter(|k| **k).count();
+    Trimmed {
+        text: output.join("\n"),
+        kept_lines,
+        dropped_lines: lines.len() - kept_lines,
+        dropped_tokens,
+        kept_error_lines,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("cargo build"), 3);
+        assert_eq!(estimate_tokens("ファイル"), 4);
+    }
+
+    #[test]
+    fn test_context_window_and_budget() {
+        assert_eq!(context_window("openai", "gpt-4o-mini"), 128_000);
+        assert_eq!(context_window("openai", "gpt-4-0613"), 8_192);
+        assert_eq!(
+            context_window("anthropic", "claude-3-5-sonnet-latest"),
+            200_000
+        );
+        assert_eq!(context_window("ollama", "llama3.2"), 4_096);
+        assert_eq!(context_window("azure", "prod-deployment"), 8_192);
+
+        assert_eq!(output_reserve(4_096, None, None), 1_024);
+        assert_eq!(output_reserve(128_000, None, Some(2_000)), 6_096);
+        assert_eq!(output_reserve(128_000, Some(500), None), 500);
+        assert_eq!(prompt_budget(4_096, 1_024), 2_663);
+        assert_eq!(prompt_budget(4_096, 8_000), 0);
+    }
+
+    #[test]
+    fn test_text_within_budget_is_kept() {
+        let trimmed = trim_lines("$ ls\nsrc", 100);
+        assert_eq!(trimmed.text, "$ ls\nsrc");
+        assert_eq!(trimmed.dropped_lines, 0);
+    }
+
+    #[test]
+    fn test_recent_lines_and_older_errors_are_kept() {
+        // Each line costs 3 tokens
+        let mut lines: Vec<String> = (0..30).map(|i| format!("line {:03}", i)).collect();
+        lines[5] = "error: E0425".to_string();
+        let trimmed = trim_lines(&lines.join("\n"), 44);
+
+        assert_eq!(
+            trimmed.text,
+            "[... 5 lines dropped ...]\nerror: E0425\n[... 16 lines dropped ...]\n\
+             line 022\nline 023\nline 024\nline 025\nline 026\nline 027\nline 028\nline 029"
+        );
+        assert_eq!(trimmed.kept_lines, 9);
+        assert_eq!(trimmed.dropped_lines, 21);
+        assert_eq!(trimmed.dropped_tokens, 63);
+        assert_eq!(trimmed.kept_error_lines, 1);
+    }
+
+    #[test]
+    fn test_nothing_fits_a_zero_budget() {
+        let trimmed = trim_lines("a\nb", 0);
+        assert_eq!(trimmed.text, "[... 2 lines dropped ...]");
+        assert_eq!(trimmed.kept_lines, 0);
+    }
+}
@@ -0,0 +1,183 @@
+//! Layout of the answers of several models asked the same question
+//!
+//! Answers are shown one after the other in labelled sections, or side by side when the
+//! terminal has room for a readable column per answer.
+
+use std::fs::File;
+use std::process::{Command, Stdio};
+
+/// Narrowest column worth showing side by side
+pub const MIN_COLUMN_WIDTH: usize = 40;
+
+const SEPARATOR: &str = " │ ";
+
+/// Width of the terminal: $COLUMNS, or else asked from the terminal itself, as stdout is
+/// captured by the shell function
+pub fn terminal_width() -> Option<usize> {
+    if let Some(columns) = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+    {
+        return Some(columns);
+    }
+    let tty = File::open("/dev/tty").ok()?;
+    let output = Command::new("stty")
+        .arg("size")
+        .stdin(tty)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    // `rows columns`
+    String::from_utf8(output.stdout)
+        .ok()?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// Width of each column when `count` answers are shown side by side, or None if they do
+/// not fit
+pub fn column_width(count: usize, terminal_width: usize) -> Option<usize> {
+    let separators = SEPARATOR.chars().count() * count.saturating_sub(1);
+    let width = terminal_width.saturating_sub(separators) / count.max(1);
+    (width >= MIN_COLUMN_WIDTH).then_some(width)
+}
+
+/// Header line of a section
+pub fn section_header(label: &str) -> String {
+    format!("── {} ──", label)
+}
+
+/// Answers in columns of the given width, each under its label
+pub fn side_by_side(answers: &[(String, String)], column_width: usize) -> String {
+    let columns: Vec<Vec<String>> = answers
+        .iter()
+        .map(|(label, text)| {
+            let mut lines = wrap(label, column_width);
+            lines.push("─".repeat(column_width));
+            lines.extend(wrap(text, column_width));
+            lines
+        })
+        .collect();
+    let height = columns.iter().map(Vec::len).max().unwrap_or(0);
+
+    let mut output = String::new();
+    for row in 0..height {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|lines| {
+                let cell = lines.get(row).map_or("", String::as_str);
+                let padding = column_width.saturating_sub(cell.chars().count());
+                format!("{}{}", cell, " ".repeat(padding))
+            })
+            .collect();
+        output.push_str(cells.join(SEPARATOR).trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+/// Break text into lines of at most `width` characters, at spaces where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.trim_end().lines() {
+        let mut line = String::new();
+        let mut length = 0;
+        for word in paragraph.split(' ') {
+            let word_length = word.chars().count();
+            if length > 0 && length + 1 + word_length > width {
+                lines.push(std::mem::take(&mut line));
+                length = 0;
+            }
+            if length > 0 {
+                line.push(' ');
+                length += 1;
+            }
+            line.push_str(word);
+            length += word_length;
+            // A word longer than the column is cut
+            while length > width {
+                let rest: String = line.chars().skip(width).collect();
+                lines.push(line.chars().take(width).collect());
+                line = rest;
+                length -= width;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Commands of all answers without duplicates, in order of first appearance, each with
+/// the labels of the answers suggesting it
+pub fn merge_commands(answers: &[(String, Vec<String>)]) -> Vec<(String, Vec<String>)> {
+    let mut merged: Vec<(String, Vec<String>)> = Vec::new();
+    for (label, commands) in answers {
+        for command in commands {
+            match merged.iter_mut().find(|(c, _)| c == command) {
+                Some((_, labels)) if labels.contains(label) => {}
+                Some((_, labels)) => labels.push(label.clone()),
+                None => merged.push((command.clone(), vec![label.clone()])),
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_width() {
+        assert_eq!(column_width(2, 120), Some(58));
+        assert_eq!(column_width(3, 120), None);
+        assert_eq!(column_width(2, 80), None);
+    }
+
+    #[test]
+    fn test_wrap_at_spaces_and_cut_long_words() {
+        assert_eq!(
+            wrap("list the files\n\nrun abcdefghij", 8),
+            vec!["list the", "files", "", "run", "abcdefgh", "ij"]
+        );
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        let answers = vec![
+            ("a/one".to_string(), "Run ls to list".to_string()),
+            ("b/two".to_string(), "Use find".to_string()),
+        ];
+        assert_eq!(
+            side_by_side(&answers, 8),
+            "a/one    │ b/two\n\
+             ──────── │ ────────\n\
+             Run ls   │ Use find\n\
+             to list  │\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_commands() {
+        let answers = vec![
+            (
+                "a".to_string(),
+                vec!["ls -la".to_string(), "du -sh".to_string()],
+            ),
+            (
+                "b".to_string(),
+                vec!["du -sh".to_string(), "du -sh".to_string()],
+            ),
+            ("c".to_string(), vec!["ls -la".to_string()]),
+        ];
+        assert_eq!(
+            merge_commands(&answers),
+            vec![
+                ("ls -la".to_string(), vec!["a".to_string(), "c".to_string()]),
+                ("du -sh".to_string(), vec!["a".to_string(), "b".to_string()]),
+            ]
+        );
+    }
+}
@@ -1,92 +1,631 @@
 use async_trait::async_trait;
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 use reqwest::{header, Client};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::time::Duration;
 
-use super::{ChatStream, LLMConfig, LLMError, LLMProvider};
+use super::http;
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::retry::retry_after;
+use super::sse::{sse_stream, SseEvent};
+use super::{
+    ChatStream, GenerationParams, LLMConfig, LLMError, LLMProvider, Message, ModelInfo,
+    OutputSchema, Role, StopReason, StreamEvent, ToolSpec, Usage,
+};
 
-const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1/messages";
+pub const ANTHROPIC_DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const MODELS_PAGE_SIZE: u32 = 1000; // The maximum of the API
+
+const ENV_ANTHROPIC_API_KEY: &str = "ASK_SH_ANTHROPIC_API_KEY";
+const ENV_ANTHROPIC_MODEL: &str = "ASK_SH_ANTHROPIC_MODEL";
+const ENV_ANTHROPIC_BASE_URL: &str = "ASK_SH_ANTHROPIC_BASE_URL";
+const ENV_ANTHROPIC_EXTRA_HEADERS: &str = "ASK_SH_ANTHROPIC_EXTRA_HEADERS";
+const ENV_ANTHROPIC_PROMPT_CACHING: &str = "ASK_SH_ANTHROPIC_PROMPT_CACHING";
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 4096; // max_tokens is required by the API
 
 #[derive(Debug)]
 pub struct AnthropicProvider {
     client: Client,
+    read_timeout: Option<Duration>,
     model: String,
     api_key: String,
+    base_url: String,
+    extra_headers: header::HeaderMap,
+    generation: GenerationParams,
+    tools: Vec<ToolSpec>,
+    output_schema: Option<OutputSchema>, // Requested as a tool the model must call
+    prompt_caching: bool,
 }
 
 #[derive(Serialize, Debug)]
 struct AnthropicRequest {
     model: String,
-    system: String,
-    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<AnthropicContent>,
+    messages: Vec<AnthropicMessage>,
     stream: bool,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<AnthropicTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Thinking>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Thinking {
+    Enabled { budget_tokens: u32 },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ToolChoice {
+    Auto,
+    Any,
+    Tool { name: String },
 }
 
 #[derive(Serialize, Debug)]
-struct Message {
+struct AnthropicMessage {
     role: String,
-    content: String,
+    content: AnthropicContent,
+}
+
+/// Plain text, or blocks once images, tool calls or results are involved
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+impl AnthropicContent {
+    fn into_blocks(self) -> Vec<ContentBlock> {
+        match self {
+            AnthropicContent::Text(text) if text.is_empty() => Vec::new(),
+            AnthropicContent::Text(text) => vec![ContentBlock::Text {
+                text,
+                cache_control: None,
+            }],
+            AnthropicContent::Blocks(blocks) => blocks,
+        }
+    }
+
+    /// Append the content of a following message of the same role
+    fn merge(&mut self, other: AnthropicContent) {
+        match (&mut *self, other) {
+            (AnthropicContent::Text(text), AnthropicContent::Text(other)) => {
+                text.push_str("\n\n");
+                text.push_str(&other);
+            }
+            (_, other) => {
+                let mut blocks =
+                    std::mem::replace(self, AnthropicContent::Text(String::new())).into_blocks();
+                blocks.extend(other.into_blocks());
+                *self = AnthropicContent::Blocks(blocks);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct ImageSource {
+    #[serde(rename = "type")]
+    source_type: &'static str,
+    media_type: &'static str,
+    data: String, // Base64
+}
+
+impl ContentBlock {
+    /// Make the prompt up to the end of this block cacheable
+    fn set_cache_breakpoint(&mut self) {
+        match self {
+            ContentBlock::Text { cache_control, .. }
+            | ContentBlock::ToolResult { cache_control, .. } => {
+                *cache_control = Some(CacheControl {
+                    cache_type: "ephemeral",
+                })
+            }
+            ContentBlock::ToolUse { .. }
+            | ContentBlock::Thinking { .. }
+            | ContentBlock::Image { .. } => {}
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    cache_type: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicTool {
+    name: String,
+    description: String,
+    input_schema: serde_json::Value,
 }
 
 #[derive(Deserialize, Debug)]
 struct AnthropicStreamEvent {
     #[serde(rename = "type")]
     event_type: String,
+    index: Option<usize>,
+    message: Option<MessageStart>,
+    content_block: Option<ContentBlockStart>,
     delta: Option<Delta>,
+    usage: Option<AnthropicUsage>,
+    error: Option<StreamError>,
+}
+
+/// `message` of the message_start event
+#[derive(Deserialize, Debug)]
+struct MessageStart {
+    id: Option<String>,
+    model: Option<String>,
+    usage: Option<AnthropicUsage>,
+}
+
+/// `content_block` of the content_block_start event
+#[derive(Deserialize, Debug)]
+struct ContentBlockStart {
+    #[serde(rename = "type")]
+    block_type: String,
+    id: Option<String>,   // Only for tool_use
+    name: Option<String>, // Only for tool_use
 }
 
 #[derive(Deserialize, Debug)]
 struct Delta {
     text: Option<String>,
+    thinking: Option<String>,     // Piece of the reasoning
+    signature: Option<String>,    // Sent once the reasoning is complete
+    partial_json: Option<String>, // Arguments of a tool call, in pieces
+    stop_reason: Option<String>,  // Only in message_delta
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicUsage {
+    input_tokens: Option<u32>, // Without the tokens read from or written to the cache
+    output_tokens: Option<u32>,
+    cache_read_input_tokens: Option<u32>,
+    cache_creation_input_tokens: Option<u32>,
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Self {
+        Usage {
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cache_read_tokens: usage.cache_read_input_tokens,
+            cache_write_tokens: usage.cache_creation_input_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamError {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: String,
+}
+
+/// Body of a non-successful response
+#[derive(Deserialize, Debug)]
+struct AnthropicErrorResponse {
+    error: StreamError,
+}
+
+/// Page of the response of `/v1/models`
+#[derive(Deserialize, Debug)]
+struct ModelPage {
+    data: Vec<AnthropicModel>,
+    #[serde(default)]
+    has_more: bool,
+    last_id: Option<String>, // Cursor for the next page
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicModel {
+    id: String,
+}
+
+/// What is known about the response while it streams
+struct StreamState {
+    output_tool: Option<String>, // Name of the tool carrying a JSON answer
+    output_block: Option<usize>, // Index of the content block calling it
 }
 
 impl AnthropicProvider {
     pub fn new(config: LLMConfig) -> Result<Self, LLMError> {
-        let client = Client::builder()
-            .build()
-            .map_err(|e| LLMError::ConfigError(e.to_string()))?;
+        let client = config.http.client()?;
+
+        let base_url = config
+            .base_url
+            .unwrap_or_else(|| ANTHROPIC_DEFAULT_BASE_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        let mut extra_headers = header::HeaderMap::new();
+        for (name, value) in &config.extra_headers {
+            let name = header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| LLMError::ConfigError(format!("Invalid header name: {}", name)))?;
+            let value = header::HeaderValue::from_str(value)
+                .map_err(|_| LLMError::ConfigError(format!("Invalid value for header {}", name)))?;
+            extra_headers.append(name, value);
+        }
 
         Ok(Self {
             client,
+            read_timeout: config.http.read_timeout,
             model: config.model,
             api_key: config.api_key,
+            base_url,
+            extra_headers,
+            generation: config.generation,
+            tools: config.tools,
+            output_schema: config.output_schema,
+            prompt_caching: config.prompt_caching,
         })
     }
 
-    fn create_request(&self, system_message: &str, user_message: &str) -> AnthropicRequest {
+    /// Add the version and authentication headers to a request
+    fn authorize(&self, request_builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let mut request_builder = request_builder.header("anthropic-version", ANTHROPIC_VERSION);
+        // Gateways may authenticate with their own headers instead of an API key
+        if !self.api_key.is_empty() {
+            request_builder = request_builder.header("x-api-key", &self.api_key);
+        }
+        request_builder.headers(self.extra_headers.clone())
+    }
+
+    /// Error described by a non-successful response
+    async fn error_from_response(response: reqwest::Response) -> LLMError {
+        let status = response.status().as_u16();
+        let retry_after = retry_after(response.headers());
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        match serde_json::from_str::<AnthropicErrorResponse>(&error_text) {
+            Ok(body) => LLMError::from_api_error(
+                Some(status),
+                Some(&body.error.error_type),
+                body.error.message,
+                retry_after,
+            ),
+            Err(_) => LLMError::from_api_error(Some(status), None, error_text, retry_after),
+        }
+    }
+
+    fn create_request(&self, messages: &[Message]) -> AnthropicRequest {
+        // Anthropic takes the system prompt as a separate field
+        let system_messages: Vec<&str> = messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(|message| message.content.as_str())
+            .collect();
+        let system = if system_messages.is_empty() {
+            None
+        } else if self.prompt_caching {
+            // The system prompt, and the tools before it, are the same for every request
+            let mut block = ContentBlock::Text {
+                text: system_messages.join("\n\n"),
+                cache_control: None,
+            };
+            block.set_cache_breakpoint();
+            Some(AnthropicContent::Blocks(vec![block]))
+        } else {
+            Some(AnthropicContent::Text(system_messages.join("\n\n")))
+        };
+
+        // Roles must alternate, so consecutive messages of the same role are merged.
+        // Tool results are sent by the user.
+        let mut conversation: Vec<AnthropicMessage> = Vec::new();
+        for message in messages.iter().filter(|m| m.role != Role::System) {
+            let (role, content) = Self::convert_message(message);
+            match conversation.last_mut() {
+                Some(last) if last.role == role => last.content.merge(content),
+                _ => conversation.push(AnthropicMessage {
+                    role: role.to_string(),
+                    content,
+                }),
+            }
+        }
+
+        // The conversation so far, including the terminal contents, is sent again in every
+        // round of tool calls
+        if self.prompt_caching {
+            if let Some(last) = conversation.last_mut() {
+                let content =
+                    std::mem::replace(&mut last.content, AnthropicContent::Text(String::new()));
+                let mut blocks = content.into_blocks();
+                if let Some(block) = blocks.last_mut() {
+                    block.set_cache_breakpoint();
+                }
+                last.content = AnthropicContent::Blocks(blocks);
+            }
+        }
+
+        let mut tools: Vec<AnthropicTool> = self
+            .tools
+            .iter()
+            .map(|tool| AnthropicTool {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                input_schema: tool.parameters.clone(),
+            })
+            .collect();
+        // A JSON answer is written as the input of a tool the model has to call.
+        // With other tools, the model may still call them before answering.
+        // Thinking rules out forcing the call, so the model is only asked to make it.
+        let thinking_budget = self.generation.thinking_budget;
+        let tool_choice = self.output_schema.as_ref().map(|output| {
+            let choice = if thinking_budget.is_some() {
+                ToolChoice::Auto
+            } else if tools.is_empty() {
+                ToolChoice::Tool {
+                    name: output.name.clone(),
+                }
+            } else {
+                ToolChoice::Any
+            };
+            tools.push(AnthropicTool {
+                name: output.name.clone(),
+                description: "Give the answer to the user.".to_string(),
+                input_schema: output.schema.clone(),
+            });
+            choice
+        });
+
         AnthropicRequest {
             model: self.model.clone(),
-            system: system_message.to_string(),
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: user_message.to_string(),
-            }],
+            system,
+            messages: conversation,
             stream: true,
-            max_tokens: 4096,
+            // The thinking budget counts towards max_tokens, so it is added to the room for the answer
+            max_tokens: self
+                .generation
+                .max_tokens
+                .unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS)
+                .saturating_add(thinking_budget.unwrap_or(0)),
+            // Sampling parameters cannot be changed while thinking
+            temperature: self
+                .generation
+                .temperature
+                .filter(|_| thinking_budget.is_none()),
+            top_p: self.generation.top_p.filter(|_| thinking_budget.is_none()),
+            stop_sequences: self.generation.stop.clone(),
+            tools,
+            tool_choice,
+            thinking: thinking_budget.map(|budget_tokens| Thinking::Enabled { budget_tokens }),
         }
     }
 
-    fn parse_sse_line(line: &str) -> Option<String> {
-        if line.is_empty() || line.starts_with(':') {
-            return None;
+    fn convert_message(message: &Message) -> (&'static str, AnthropicContent) {
+        match message.role {
+            Role::Tool => (
+                Role::User.as_str(),
+                AnthropicContent::Blocks(vec![ContentBlock::ToolResult {
+                    tool_use_id: message.tool_call_id.clone().unwrap_or_default(),
+                    content: message.content.clone(),
+                    cache_control: None,
+                }]),
+            ),
+            role if message.tool_calls.is_empty() && message.images.is_empty() => (
+                role.as_str(),
+                AnthropicContent::Text(message.content.clone()),
+            ),
+            // Images work best before the text about them
+            role if message.tool_calls.is_empty() => {
+                let mut blocks: Vec<ContentBlock> = message
+                    .images
+                    .iter()
+                    .map(|image| ContentBlock::Image {
+                        source: ImageSource {
+                            source_type: "base64",
+                            media_type: image.media_type,
+                            data: image.data.clone(),
+                        },
+                    })
+                    .collect();
+                blocks.extend(AnthropicContent::Text(message.content.clone()).into_blocks());
+                (role.as_str(), AnthropicContent::Blocks(blocks))
+            }
+            role => {
+                // The reasoning before tool calls has to be sent back while thinking
+                let mut blocks: Vec<ContentBlock> = message
+                    .reasoning
+                    .iter()
+                    .filter_map(|reasoning| {
+                        Some(ContentBlock::Thinking {
+                            thinking: reasoning.text.clone(),
+                            signature: reasoning.signature.clone()?,
+                        })
+                    })
+                    .collect();
+                blocks.extend(AnthropicContent::Text(message.content.clone()).into_blocks());
+                blocks.extend(message.tool_calls.iter().map(|call| {
+                    ContentBlock::ToolUse {
+                        id: call.id.clone(),
+                        name: call.name.clone(),
+                        // Arguments that are not valid JSON were rejected when running the tool
+                        input: call
+                            .parsed_arguments()
+                            .unwrap_or_else(|_| serde_json::json!({})),
+                    }
+                }));
+                (role.as_str(), AnthropicContent::Blocks(blocks))
+            }
         }
+    }
 
-        if let Some(data) = line.strip_prefix("data: ") {
-            if data.trim() == "[DONE]" {
-                return None;
+    fn parse_event(
+        event: &SseEvent,
+        state: &mut StreamState,
+    ) -> Vec<Result<StreamEvent, LLMError>> {
+        let parsed = match serde_json::from_str::<AnthropicStreamEvent>(&event.data) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return vec![Err(LLMError::ApiError(format!(
+                    "Invalid event from Anthropic: {}",
+                    e
+                )))]
             }
+        };
 
-            if let Ok(event) = serde_json::from_str::<AnthropicStreamEvent>(data) {
-                if event.event_type == "content_block_delta" {
-                    if let Some(delta) = event.delta {
-                        return delta.text;
+        let mut events = Vec::new();
+        match parsed.event_type.as_str() {
+            "message_start" => {
+                if let Some(message) = parsed.message {
+                    events.push(Ok(StreamEvent::Metadata {
+                        id: message.id,
+                        model: message.model,
+                    }));
+                    events.extend(message.usage.map(|u| Ok(StreamEvent::Usage(u.into()))));
+                }
+            }
+            "content_block_start" => {
+                let Some(block) = parsed.content_block.filter(|b| b.block_type == "tool_use")
+                else {
+                    return events;
+                };
+                if block.name.is_some() && block.name == state.output_tool {
+                    state.output_block = parsed.index;
+                } else {
+                    events.push(Ok(StreamEvent::ToolCallStart {
+                        index: parsed.index.unwrap_or_default(),
+                        id: block.id.unwrap_or_default(),
+                        name: block.name.unwrap_or_default(),
+                    }));
+                }
+            }
+            "content_block_delta" => {
+                if let Some(delta) = parsed.delta {
+                    if let Some(thinking) = delta.thinking.filter(|t| !t.is_empty()) {
+                        events.push(Ok(StreamEvent::ReasoningDelta(thinking)));
+                    }
+                    if let Some(signature) = delta.signature {
+                        events.push(Ok(StreamEvent::ReasoningSignature(signature)));
+                    }
+                    if let Some(text) = delta.text {
+                        events.push(Ok(StreamEvent::TextDelta(text)));
+                    }
+                    let partial_json = delta.partial_json.filter(|json| !json.is_empty());
+                    if let Some(json) = partial_json.as_ref().filter(|_| {
+                        state.output_block.is_some() && parsed.index == state.output_block
+                    }) {
+                        // The JSON answer is the text of the answer
+                        events.push(Ok(StreamEvent::TextDelta(json.clone())));
+                    } else if let Some(arguments) = partial_json {
+                        events.push(Ok(StreamEvent::ToolCallDelta {
+                            index: parsed.index.unwrap_or_default(),
+                            arguments,
+                        }));
                     }
                 }
             }
+            "message_delta" => {
+                if let Some(reason) = parsed.delta.and_then(|delta| delta.stop_reason) {
+                    let reason = match StopReason::from_provider(&reason) {
+                        // Calling the answer tool ends the answer
+                        StopReason::ToolUse if state.output_block.is_some() => StopReason::EndTurn,
+                        reason => reason,
+                    };
+                    events.push(Ok(StreamEvent::Stop(reason)));
+                }
+                events.extend(parsed.usage.map(|u| Ok(StreamEvent::Usage(u.into()))));
+            }
+            "error" => {
+                if let Some(error) = parsed.error {
+                    // Errors sent mid-stream carry no HTTP status, so use the one documented for the type
+                    events.push(Err(LLMError::from_api_error(
+                        Self::status_for_error_type(&error.error_type),
+                        Some(&error.error_type),
+                        error.message,
+                        None,
+                    )));
+                }
+            }
+            _ => {}
         }
-        None
+        events
+    }
+
+    fn status_for_error_type(error_type: &str) -> Option<u16> {
+        match error_type {
+            "invalid_request_error" => Some(400),
+            "authentication_error" => Some(401),
+            "permission_error" => Some(403),
+            "not_found_error" => Some(404),
+            "request_too_large" => Some(413),
+            "rate_limit_error" => Some(429),
+            "api_error" => Some(500),
+            "overloaded_error" => Some(529),
+            _ => None,
+        }
+    }
+
+    /// Convert the raw response body into a stream of events.
+    /// `output_tool` is the name of the tool that carries a JSON answer.
+    fn event_stream<S, B>(
+        bytes: S,
+        output_tool: Option<String>,
+    ) -> impl Stream<Item = Result<StreamEvent, LLMError>>
+    where
+        S: Stream<Item = Result<B, reqwest::Error>> + Unpin,
+        B: AsRef<[u8]>,
+    {
+        let state = StreamState {
+            output_tool,
+            output_block: None,
+        };
+        sse_stream(bytes)
+            .scan(state, |state, result| {
+                futures::future::ready(Some(match result {
+                    Ok(event) => Self::parse_event(&event, state),
+                    Err(e) => vec![Err(LLMError::StreamInterrupted(e.to_string()))],
+                }))
+            })
+            .flat_map(futures::stream::iter)
     }
 }
 
@@ -100,69 +639,164 @@ impl LLMProvider for AnthropicProvider {
         &self.model
     }
 
-    async fn chat_stream(
-        &self,
-        system_message: String,
-        user_message: String,
-    ) -> Result<ChatStream, LLMError> {
-        let request = self.create_request(&system_message, &user_message);
-
-        let response = self
-            .client
-            .post(ANTHROPIC_API_URL)
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let request = self.create_request(&messages);
+
+        let request_builder = self
+            .authorize(self.client.post(format!("{}/v1/messages", self.base_url)))
             .header(header::CONTENT_TYPE, "application/json")
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| LLMError::NetworkError(e.to_string()))?;
+            .json(&request);
+        let response = http::send(request_builder, self.read_timeout).await?;
 
         if !response.status().is_success() {
-            let error_text = response
-                .text()
+            return Err(Self::error_from_response(response).await);
+        }
+
+        let output_tool = self.output_schema.as_ref().map(|o| o.name.clone());
+        Ok(http::with_read_timeout(
+            Box::pin(Self::event_stream(response.bytes_stream(), output_tool)),
+            self.read_timeout,
+        ))
+    }
+
+    fn supports_images(&self) -> bool {
+        true
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        let mut models = Vec::new();
+        let mut after_id: Option<String> = None;
+        loop {
+            let mut request_builder = self
+                .authorize(self.client.get(format!("{}/v1/models", self.base_url)))
+                .query(&[("limit", MODELS_PAGE_SIZE.to_string())]);
+            if let Some(after_id) = &after_id {
+                request_builder = request_builder.query(&[("after_id", after_id)]);
+            }
+            let response = http::send(request_builder, self.read_timeout).await?;
+            if !response.status().is_success() {
+                return Err(Self::error_from_response(response).await);
+            }
+
+            let page: ModelPage = response
+                .json()
                 .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LLMError::ApiError(format!(
-                "Anthropic API error: {}",
-                error_text
-            )));
+                .map_err(|e| LLMError::ApiError(format!("Invalid model list: {}", e)))?;
+            // The API does not tell the context size
+            models.extend(page.data.into_iter().map(|model| ModelInfo {
+                id: model.id,
+                context_length: None,
+            }));
+            match page.last_id {
+                Some(last_id) if page.has_more => after_id = Some(last_id),
+                _ => break,
+            }
         }
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(models)
+    }
+}
 
-        let stream = response.bytes_stream().map(move |result| match result {
-            Ok(bytes) => {
-                let text = String::from_utf8_lossy(&bytes);
-                let mut content = String::new();
+pub struct AnthropicFactory;
 
-                for line in text.lines() {
-                    if let Some(text) = Self::parse_sse_line(line) {
-                        content.push_str(&text);
-                    }
-                }
+impl ProviderFactory for AnthropicFactory {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
 
-                if !content.is_empty() {
-                    Ok(content)
-                } else {
-                    Ok(String::new())
-                }
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::api_key(ENV_ANTHROPIC_API_KEY),
+            Setting::model(ENV_ANTHROPIC_MODEL).with_default("claude-3-5-sonnet-latest"),
+            Setting::endpoint(ENV_ANTHROPIC_BASE_URL),
+            Setting::other(ENV_ANTHROPIC_EXTRA_HEADERS),
+            Setting::other(ENV_ANTHROPIC_PROMPT_CACHING),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        let base_url = settings.get(ENV_ANTHROPIC_BASE_URL);
+
+        // A gateway behind a custom endpoint may handle authentication itself
+        let api_key = match settings.get(ENV_ANTHROPIC_API_KEY) {
+            Some(api_key) => api_key,
+            None if base_url.is_some() => String::new(),
+            None => {
+                return Err(LLMError::ConfigError(
+                    "Anthropic API key not found".to_string(),
+                ))
             }
-            Err(e) => Err(LLMError::NetworkError(e.to_string())),
-        });
+        };
 
-        let filtered_stream = stream.filter(|result| {
-            futures::future::ready(match result {
-                Ok(content) => !content.is_empty(),
-                Err(_) => true,
-            })
-        });
+        let extra_headers = match settings.get(ENV_ANTHROPIC_EXTRA_HEADERS) {
+            Some(value) => parse_extra_headers(&value)?,
+            None => Vec::new(),
+        };
 
-        Ok(Box::pin(filtered_stream))
+        Ok(LLMConfig {
+            api_key,
+            model: settings.require(ENV_ANTHROPIC_MODEL)?,
+            base_url,
+            extra_headers,
+            prompt_caching: settings.flag(ENV_ANTHROPIC_PROMPT_CACHING),
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(AnthropicProvider::new(config)?))
     }
 }
 
+/// Parse headers written as `Name: value` pairs separated by `;`. A `;` followed by
+/// something other than a header name belongs to the value, e.g. in `cookie: a=1; b=2`.
+fn parse_extra_headers(value: &str) -> Result<Vec<(String, String)>, LLMError> {
+    let mut headers: Vec<String> = Vec::new();
+    for part in value.split(';') {
+        match headers.last_mut() {
+            Some(header) if !starts_with_header_name(part) => {
+                header.push(';');
+                header.push_str(part);
+            }
+            _ => headers.push(part.to_string()),
+        }
+    }
+
+    headers
+        .iter()
+        .map(|header| header.trim())
+        .filter(|header| !header.is_empty())
+        .map(|header| match header.split_once(':') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(LLMError::ConfigError(format!(
+                "Invalid header (expected `Name: value`): {}",
+                header
+            ))),
+        })
+        .collect()
+}
+
+/// Whether the text starts with `Name:`, where the name is a valid HTTP header name
+fn starts_with_header_name(text: &str) -> bool {
+    text.trim_start().split_once(':').is_some_and(|(name, _)| {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::image::Image;
+    use crate::llm::stub_server::{self, StubResponse};
+    use crate::llm::{Reasoning, ToolCall};
 
     #[tokio::test]
     async fn test_anthropic_provider_creation() {
@@ -171,10 +805,592 @@ mod tests {
             model: "claude-3-opus-20240229".to_string(),
             api_key: "test-key".to_string(),
             base_url: None,
+            ..Default::default()
         };
 
         let provider = AnthropicProvider::new(config).unwrap();
         assert_eq!(provider.name(), "anthropic");
         assert_eq!(provider.model(), "claude-3-opus-20240229");
     }
+
+    #[test]
+    fn test_anthropic_request_separates_system_and_history() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            api_key: "test-key".to_string(),
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let request = provider.create_request(&[
+            Message::system("be brief"),
+            Message::user("what is ls"),
+            Message::assistant("it lists files"),
+            Message::user("and ls -a?"),
+            Message::user("and ls -l?"),
+        ]);
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["system"], "be brief");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "it lists files");
+        assert_eq!(messages[2]["content"], "and ls -a?\n\nand ls -l?");
+        assert_eq!(body["max_tokens"], ANTHROPIC_DEFAULT_MAX_TOKENS);
+        assert!(body.get("temperature").is_none());
+    }
+
+    #[test]
+    fn test_anthropic_request_generation_params() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            generation: GenerationParams {
+                max_tokens: Some(8192),
+                temperature: Some(0.0),
+                top_p: None,
+                stop: vec!["END".to_string()],
+                seed: Some(42),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let body = serde_json::to_value(provider.create_request(&[Message::user("hi")])).unwrap();
+        assert_eq!(body["max_tokens"], 8192);
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["stop_sequences"], serde_json::json!(["END"]));
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("seed").is_none());
+    }
+
+    #[test]
+    fn test_anthropic_request_enables_thinking() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-sonnet-4-0".to_string(),
+            generation: GenerationParams {
+                max_tokens: Some(2000),
+                temperature: Some(0.0),
+                thinking_budget: Some(1024),
+                ..Default::default()
+            },
+            output_schema: Some(OutputSchema {
+                name: "answer".to_string(),
+                schema: serde_json::json!({"type": "object"}),
+            }),
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let body = serde_json::to_value(provider.create_request(&[Message::user("hi")])).unwrap();
+        assert_eq!(
+            body["thinking"],
+            serde_json::json!({"type": "enabled", "budget_tokens": 1024})
+        );
+        assert_eq!(body["max_tokens"], 3024);
+        assert!(body.get("temperature").is_none());
+        // Calls cannot be forced while thinking
+        assert_eq!(body["tool_choice"], serde_json::json!({"type": "auto"}));
+    }
+
+    #[test]
+    fn test_anthropic_request_sends_thinking_back_with_tool_calls() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-sonnet-4-0".to_string(),
+            generation: GenerationParams {
+                thinking_budget: Some(1024),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let call = ToolCall {
+            id: "toolu_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: "{}".to_string(),
+        };
+        let reasoning = Reasoning {
+            text: "The manifest will tell.".to_string(),
+            signature: Some("c2lnbmVk".to_string()),
+        };
+        let body = serde_json::to_value(provider.create_request(&[
+            Message::user("what is this project"),
+            Message::tool_calls("", vec![call]).with_reasoning(reasoning),
+            Message::tool_result("toolu_1", "[package]"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            body["messages"][1]["content"][0],
+            serde_json::json!({"type": "thinking", "thinking": "The manifest will tell.", "signature": "c2lnbmVk"})
+        );
+        assert_eq!(body["messages"][1]["content"][1]["type"], "tool_use");
+    }
+
+    #[test]
+    fn test_anthropic_request_attaches_images() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let image = Image::from_bytes(b"\xff\xd8\xff").unwrap();
+        let body = serde_json::to_value(provider.create_request(&[
+            Message::user("terminal:\n$ plot"),
+            Message::user("what is this chart").with_images(vec![image]),
+        ]))
+        .unwrap();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "terminal:\n$ plot"},
+                {"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "/9j/"}},
+                {"type": "text", "text": "what is this chart"},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_marks_cache_breakpoints() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            prompt_caching: true,
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let request = provider.create_request(&[
+            Message::system("be brief"),
+            Message::user("terminal:\n$ ls"),
+            Message::user("what is this?"),
+        ]);
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            body["system"],
+            serde_json::json!([{"type": "text", "text": "be brief", "cache_control": {"type": "ephemeral"}}])
+        );
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0]["content"],
+            serde_json::json!([{"type": "text", "text": "terminal:\n$ ls\n\nwhat is this?", "cache_control": {"type": "ephemeral"}}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_survives_byte_sized_chunks() {
+        let body = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"model\":\"claude-3-5-sonnet-20241022\",\"usage\":{\"input_tokens\":25,\"cache_read_input_tokens\":1800,\"cache_creation_input_tokens\":0,\"output_tokens\":1}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"ファイルを\"}}\n\n",
+            "event: ping\n",
+            "data: {\"type\":\"ping\"}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"削除します\"}}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"max_tokens\",\"stop_sequence\":null},\"usage\":{\"output_tokens\":4096}}\n\n",
+            "event: message_stop\n",
+            "data: {\"type\":\"message_stop\"}\n\n",
+        );
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> =
+            body.as_bytes().chunks(1).map(|c| Ok(c.to_vec())).collect();
+
+        let events: Vec<StreamEvent> =
+            AnthropicProvider::event_stream(futures::stream::iter(chunks), None)
+                .map(|r| r.unwrap())
+                .collect()
+                .await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Metadata {
+                    id: Some("msg_1".to_string()),
+                    model: Some("claude-3-5-sonnet-20241022".to_string()),
+                },
+                StreamEvent::Usage(Usage {
+                    input_tokens: Some(25),
+                    output_tokens: Some(1),
+                    cache_read_tokens: Some(1800),
+                    cache_write_tokens: Some(0),
+                }),
+                StreamEvent::TextDelta("ファイルを".to_string()),
+                StreamEvent::TextDelta("削除します".to_string()),
+                StreamEvent::Stop(StopReason::MaxTokens),
+                StreamEvent::Usage(Usage {
+                    input_tokens: None,
+                    output_tokens: Some(4096),
+                    ..Default::default()
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_reports_thinking() {
+        let body = concat!(
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"thinking\",\"thinking\":\"\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"thinking_delta\",\"thinking\":\"Files first.\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"signature_delta\",\"signature\":\"c2lnbmVk\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"text_delta\",\"text\":\"Run ls.\"}}\n\n",
+        );
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let events: Vec<StreamEvent> =
+            AnthropicProvider::event_stream(futures::stream::iter(chunks), None)
+                .map(|r| r.unwrap())
+                .collect()
+                .await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::ReasoningDelta("Files first.".to_string()),
+                StreamEvent::ReasoningSignature("c2lnbmVk".to_string()),
+                StreamEvent::TextDelta("Run ls.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_tool_use_and_results() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            tools: vec![ToolSpec {
+                name: "list_directory".to_string(),
+                description: "List a directory".to_string(),
+                parameters: serde_json::json!({"type": "object"}),
+            }],
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let calls = vec![
+            ToolCall {
+                id: "toolu_1".to_string(),
+                name: "list_directory".to_string(),
+                arguments: "{\"path\": \"src\"}".to_string(),
+            },
+            ToolCall {
+                id: "toolu_2".to_string(),
+                name: "list_directory".to_string(),
+                arguments: String::new(),
+            },
+        ];
+        let body = serde_json::to_value(provider.create_request(&[
+            Message::user("what is in src"),
+            Message::tool_calls("Let me look.", calls),
+            Message::tool_result("toolu_1", "main.rs"),
+            Message::tool_result("toolu_2", "src"),
+        ]))
+        .unwrap();
+        assert_eq!(body["tools"][0]["name"], "list_directory");
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["content"], "what is in src");
+        assert_eq!(
+            messages[1]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "Let me look."},
+                {"type": "tool_use", "id": "toolu_1", "name": "list_directory", "input": {"path": "src"}},
+                {"type": "tool_use", "id": "toolu_2", "name": "list_directory", "input": {}},
+            ])
+        );
+        // Both results go back in a single user message
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(
+            messages[2]["content"],
+            serde_json::json!([
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": "main.rs"},
+                {"type": "tool_result", "tool_use_id": "toolu_2", "content": "src"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_reports_tool_use() {
+        let body = concat!(
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Checking.\"}}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_1\",\"name\":\"read_file\",\"input\":{}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"path\\\": \\\"a.txt\\\"}\"}}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"},\"usage\":{\"output_tokens\":40}}\n\n",
+        );
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let events: Vec<StreamEvent> =
+            AnthropicProvider::event_stream(futures::stream::iter(chunks), None)
+                .map(|r| r.unwrap())
+                .collect()
+                .await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::TextDelta("Checking.".to_string()),
+                StreamEvent::ToolCallStart {
+                    index: 1,
+                    id: "toolu_1".to_string(),
+                    name: "read_file".to_string(),
+                },
+                StreamEvent::ToolCallDelta {
+                    index: 1,
+                    arguments: "{\"path\": \"a.txt\"}".to_string(),
+                },
+                StreamEvent::Stop(StopReason::ToolUse),
+                StreamEvent::Usage(Usage {
+                    input_tokens: None,
+                    output_tokens: Some(40),
+                    ..Default::default()
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_forces_answer_tool() {
+        let output_schema = Some(OutputSchema {
+            name: "answer".to_string(),
+            schema: serde_json::json!({"type": "object"}),
+        });
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            output_schema: output_schema.clone(),
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+        let body = serde_json::to_value(provider.create_request(&[Message::user("hi")])).unwrap();
+        assert_eq!(body["tools"][0]["name"], "answer");
+        assert_eq!(
+            body["tool_choice"],
+            serde_json::json!({"type": "tool", "name": "answer"})
+        );
+
+        // Other tools may still be called before answering
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            tools: vec![ToolSpec {
+                name: "read_file".to_string(),
+                description: "Read a file".to_string(),
+                parameters: serde_json::json!({"type": "object"}),
+            }],
+            output_schema,
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+        let body = serde_json::to_value(provider.create_request(&[Message::user("hi")])).unwrap();
+        assert_eq!(body["tools"].as_array().unwrap().len(), 2);
+        assert_eq!(body["tool_choice"], serde_json::json!({"type": "any"}));
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_turns_answer_tool_into_text() {
+        let body = concat!(
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_1\",\"name\":\"answer\",\"input\":{}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"answer\\\": \"}}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"}}\n\n",
+        );
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let events: Vec<StreamEvent> = AnthropicProvider::event_stream(
+            futures::stream::iter(chunks),
+            Some("answer".to_string()),
+        )
+        .map(|r| r.unwrap())
+        .collect()
+        .await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::TextDelta("{\"answer\": ".to_string()),
+                StreamEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_reports_error_events() {
+        let body = "event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n";
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let results: Vec<Result<StreamEvent, LLMError>> =
+            AnthropicProvider::event_stream(futures::stream::iter(chunks), None)
+                .collect()
+                .await;
+        assert_eq!(results.len(), 1);
+        let err = results[0].as_ref().unwrap_err();
+        assert!(matches!(err, LLMError::Overloaded { .. }));
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_custom_base_url_and_extra_headers() {
+        let body = concat!(
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"hi\"}}\n\n",
+        );
+        let response = StubResponse::new(200, "text/event-stream", body);
+        let (base_url, server) = stub_server::serve(vec![response]).await;
+
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            api_key: "test-key".to_string(),
+            base_url: Some(format!("{}/gateway/", base_url)),
+            extra_headers: vec![
+                ("x-gateway-token".to_string(), "secret".to_string()),
+                (
+                    "anthropic-beta".to_string(),
+                    "feature-a,feature-b".to_string(),
+                ),
+            ],
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let stream = provider
+            .chat_stream(vec![Message::user("hello")])
+            .await
+            .unwrap();
+        let events: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(events, vec![StreamEvent::TextDelta("hi".to_string())]);
+
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /gateway/v1/messages HTTP/1.1"
+        );
+        assert_eq!(requests[0].header("x-api-key"), Some("test-key"));
+        assert_eq!(requests[0].header("x-gateway-token"), Some("secret"));
+        assert_eq!(
+            requests[0].header("anthropic-beta"),
+            Some("feature-a,feature-b")
+        );
+    }
+
+    #[test]
+    fn test_extra_headers_are_parsed() {
+        assert_eq!(
+            parse_extra_headers("x-gateway-token: xxx; cookie: a=1; b=2; anthropic-beta: x")
+                .unwrap(),
+            vec![
+                ("x-gateway-token".to_string(), "xxx".to_string()),
+                ("cookie".to_string(), "a=1; b=2".to_string()),
+                ("anthropic-beta".to_string(), "x".to_string()),
+            ]
+        );
+        assert!(matches!(
+            parse_extra_headers("no value"),
+            Err(LLMError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_extra_header_is_rejected() {
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            extra_headers: vec![("bad header".to_string(), "value".to_string())],
+            ..Default::default()
+        };
+        assert!(matches!(
+            AnthropicProvider::new(config),
+            Err(LLMError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_error_body_is_classified() {
+        let body = "{\"type\":\"error\",\"error\":{\"type\":\"invalid_request_error\",\"message\":\"prompt is too long: 215000 tokens > 200000 maximum\"}}";
+        let response = StubResponse::new(400, "application/json", body);
+        let (base_url, _server) = stub_server::serve(vec![response]).await;
+
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-sonnet-latest".to_string(),
+            api_key: "test-key".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let Err(err) = provider.chat_stream(vec![Message::user("hi")]).await else {
+            panic!("expected an error");
+        };
+        match err {
+            LLMError::ContextTooLong(message) => assert!(message.starts_with("prompt is too long")),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_models_follows_pages() {
+        let first = r#"{"data": [{"type": "model", "id": "claude-3-opus-20240229"}], "has_more": true, "first_id": "claude-3-opus-20240229", "last_id": "claude-3-opus-20240229"}"#;
+        let second = r#"{"data": [{"type": "model", "id": "claude-3-5-haiku-20241022"}], "has_more": false, "first_id": "claude-3-5-haiku-20241022", "last_id": "claude-3-5-haiku-20241022"}"#;
+        let (base_url, server) = stub_server::serve(vec![
+            StubResponse::new(200, "application/json", first),
+            StubResponse::new(200, "application/json", second),
+        ])
+        .await;
+
+        let config = LLMConfig {
+            provider: "anthropic".to_string(),
+            model: "claude-3-5-haiku-20241022".to_string(),
+            api_key: "test-key".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = AnthropicProvider::new(config).unwrap();
+
+        let models = provider.list_models().await.unwrap();
+        let ids: Vec<&str> = models.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec!["claude-3-5-haiku-20241022", "claude-3-opus-20240229"]
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "GET /v1/models?limit=1000 HTTP/1.1"
+        );
+        assert_eq!(
+            requests[1].request_line,
+            "GET /v1/models?limit=1000&after_id=claude-3-opus-20240229 HTTP/1.1"
+        );
+        assert_eq!(requests[1].header("x-api-key"), Some("test-key"));
+        assert_eq!(
+            requests[1].header("anthropic-version"),
+            Some(ANTHROPIC_VERSION)
+        );
+    }
 }
@@ -0,0 +1,507 @@
+//! On-disk cache of complete answers
+//!
+//! Answers are stored one file per conversation, keyed by a hash of the provider settings
+//! that shape the answer and of the messages. A cached answer is replayed in one piece,
+//! without contacting the provider. Failing to read or write the cache never fails a request.
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{
+    ChatStream, Completion, LLMConfig, LLMError, LLMProvider, Message, ModelInfo, StopReason,
+    StreamEvent,
+};
+
+/// Cache settings
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    pub ttl: Duration,  // Age after which an answer is no longer replayed
+    pub max_bytes: u64, // Oldest answers are removed beyond this total size
+}
+
+impl CacheConfig {
+    /// `$XDG_CACHE_HOME/ask-sh`, or `~/.cache/ask-sh`
+    pub fn default_dir() -> Option<PathBuf> {
+        let non_empty = |key: &str| std::env::var_os(key).filter(|value| !value.is_empty());
+        let base = match non_empty("XDG_CACHE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(non_empty("HOME")?).join(".cache"),
+        };
+        Some(base.join("ask-sh"))
+    }
+}
+
+/// Answer as stored on disk
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    text: String,
+    stop_reason: Option<String>,
+    model: Option<String>,
+}
+
+/// Directory of cached answers
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    config: CacheConfig,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.config.dir
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.config.dir.join(format!("{}.json", key))
+    }
+
+    /// Answer stored under the key, unless it has expired
+    pub fn get(&self, key: &str) -> Option<Completion> {
+        let path = self.path(key);
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age >= self.config.ttl {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        let entry: Entry = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+        Some(Completion {
+            text: entry.text,
+            stop_reason: entry.stop_reason.as_deref().map(StopReason::from_provider),
+            model: entry.model,
+            ..Completion::default()
+        })
+    }
+
+    /// Store an answer, then remove expired and excess answers
+    pub fn put(&self, key: &str, completion: &Completion) -> io::Result<()> {
+        let entry = Entry {
+            text: completion.text.clone(),
+            stop_reason: completion.stop_reason.as_ref().map(stop_reason_name),
+            model: completion.model.clone(),
+        };
+        fs::create_dir_all(&self.config.dir)?;
+        // Written aside and renamed, so that another shell never reads half an answer
+        let temporary = self
+            .config
+            .dir
+            .join(format!(".{}.{}.tmp", key, std::process::id()));
+        fs::write(&temporary, serde_json::to_vec(&entry)?)?;
+        fs::rename(&temporary, self.path(key))?;
+        self.prune()
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for path in self.entry_paths()? {
+            // Another `ask` may have removed the answer meanwhile
+            let metadata = match fs::metadata(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            let age = metadata.modified()?.elapsed().unwrap_or_default();
+            if age >= self.config.ttl {
+                remove_entry(&path)?;
+            } else {
+                entries.push((age, metadata.len(), path));
+            }
+        }
+        // Newest first, so the answers beyond the size limit are the oldest
+        entries.sort_by_key(|(age, _, _)| *age);
+        let mut total = 0;
+        for (_, size, path) in entries {
+            total += size;
+            if total > self.config.max_bytes {
+                remove_entry(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove every cached answer, returning how many there were
+    pub fn clear(&self) -> io::Result<usize> {
+        let paths = match self.entry_paths() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            result => result?,
+        };
+        for path in &paths {
+            fs::remove_file(path)?;
+        }
+        Ok(paths.len())
+    }
+
+    fn entry_paths(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Remove a cached answer, unless another `ask` already did
+fn remove_entry(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Name of a stop reason, as read back by `StopReason::from_provider`
+fn stop_reason_name(reason: &StopReason) -> String {
+    match reason {
+        StopReason::EndTurn => "end_turn",
+        StopReason::MaxTokens => "max_tokens",
+        StopReason::StopSequence => "stop_sequence",
+        StopReason::ToolUse => "tool_use",
+        StopReason::Other(other) => other,
+    }
+    .to_string()
+}
+
+/// Settings that shape the answer, without secrets and transport settings
+fn scope(config: &LLMConfig) -> String {
+    fn strip(config: &mut LLMConfig) {
+        config.api_key.clear();
+        config.extra_headers.clear();
+        config.http = Default::default();
+        config.debug = false;
+        config.fallback.iter_mut().for_each(strip);
+    }
+    let mut config = config.clone();
+    strip(&mut config);
+    format!("{:?}", config)
+}
+
+/// Provider wrapper replaying cached answers and storing new ones
+#[derive(Debug)]
+pub struct CacheProvider<P: LLMProvider> {
+    inner: P,
+    cache: ResponseCache,
+    scope: String,
+    debug: bool, // Report hits and misses to stderr
+}
+
+impl<P: LLMProvider> CacheProvider<P> {
+    /// `config` is the configuration `inner` was created from
+    pub fn new(inner: P, cache: ResponseCache, config: &LLMConfig, debug: bool) -> Self {
+        Self {
+            inner,
+            cache,
+            scope: scope(config),
+            debug,
+        }
+    }
+
+    /// Lowercase hex SHA-256 of the settings and the messages
+    fn key(&self, messages: &[Message]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.scope.as_bytes());
+        hasher.update(format!("{:?}", messages).as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn lookup(&self, key: &str) -> Option<Completion> {
+        let completion = self.cache.get(key);
+        if self.debug {
+            let outcome = if completion.is_some() { "hit" } else { "miss" };
+            eprintln!("cache {}: {}", outcome, key);
+        }
+        completion
+    }
+}
+
+/// Store a finished answer. Answers calling tools are not stored, as they depend
+/// on what the tools find when run, nor answers cut short, e.g. at the maximum length.
+fn store(cache: &ResponseCache, key: &str, completion: &Completion, debug: bool) {
+    let finished = matches!(
+        completion.stop_reason,
+        Some(StopReason::EndTurn | StopReason::StopSequence)
+    );
+    if !finished || completion.text.is_empty() || !completion.tool_calls.is_empty() {
+        return;
+    }
+    if let Err(e) = cache.put(key, completion) {
+        if debug {
+            eprintln!("cache write failed: {}", e);
+        }
+    }
+}
+
+/// State of a stream recorded while it is passed on
+struct Recording {
+    stream: ChatStream,
+    events: Vec<StreamEvent>,
+    cache: ResponseCache,
+    key: String,
+    debug: bool,
+}
+
+#[async_trait]
+impl<P: LLMProvider> LLMProvider for CacheProvider<P> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let key = self.key(&messages);
+        if let Some(completion) = self.lookup(&key) {
+            return Ok(completion.into_stream());
+        }
+
+        let recording = Recording {
+            stream: self.inner.chat_stream(messages).await?,
+            events: Vec::new(),
+            cache: self.cache.clone(),
+            key,
+            debug: self.debug,
+        };
+        // Stored when the stream ends without an error
+        Ok(Box::pin(stream::unfold(
+            Some(recording),
+            |recording| async move {
+                let mut recording = recording?;
+                match recording.stream.next().await {
+                    Some(Ok(event)) => {
+                        recording.events.push(event.clone());
+                        Some((Ok(event), Some(recording)))
+                    }
+                    Some(Err(error)) => Some((Err(error), None)),
+                    None => {
+                        let events = stream::iter(recording.events.into_iter().map(Ok));
+                        if let Ok(completion) = Completion::from_stream(Box::pin(events)).await {
+                            store(
+                                &recording.cache,
+                                &recording.key,
+                                &completion,
+                                recording.debug,
+                            );
+                        }
+                        None
+                    }
+                }
+            },
+        )))
+    }
+
+    async fn complete(&self, messages: Vec<Message>) -> Result<Completion, LLMError> {
+        let key = self.key(&messages);
+        if let Some(completion) = self.lookup(&key) {
+            return Ok(completion);
+        }
+        let completion = self.inner.complete(messages).await?;
+        store(&self.cache, &key, &completion, self.debug);
+        Ok(completion)
+    }
+
+    fn supports_images(&self) -> bool {
+        self.inner.supports_images()
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        self.inner.list_models().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::GenerationParams;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    /// Provider answering with the same text, counting its calls
+    #[derive(Debug, Default)]
+    struct CountingProvider {
+        calls: AtomicUsize,
+        tool_call: bool,
+        truncated: bool, // Stop at the maximum length
+    }
+
+    #[async_trait]
+    impl LLMProvider for CountingProvider {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn model(&self) -> &str {
+            "counting"
+        }
+
+        async fn chat_stream(&self, _messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut events = vec![
+                Ok(StreamEvent::TextDelta("Run ".to_string())),
+                Ok(StreamEvent::TextDelta("```ls```".to_string())),
+            ];
+            if self.tool_call {
+                events.push(Ok(StreamEvent::ToolCallStart {
+                    index: 0,
+                    id: "call_1".to_string(),
+                    name: "read_file".to_string(),
+                }));
+            }
+            events.push(Ok(StreamEvent::Stop(if self.truncated {
+                StopReason::MaxTokens
+            } else {
+                StopReason::EndTurn
+            })));
+            Ok(Box::pin(stream::iter(events)))
+        }
+    }
+
+    fn cache(dir: &Path) -> ResponseCache {
+        ResponseCache::new(CacheConfig {
+            dir: dir.to_path_buf(),
+            ttl: Duration::from_secs(60),
+            max_bytes: 1 << 20,
+        })
+    }
+
+    fn config(temperature: f32) -> LLMConfig {
+        LLMConfig {
+            provider: "counting".to_string(),
+            model: "counting".to_string(),
+            api_key: "secret".to_string(),
+            generation: GenerationParams {
+                temperature: Some(temperature),
+                ..GenerationParams::default()
+            },
+            ..LLMConfig::default()
+        }
+    }
+
+    async fn text(provider: &impl LLMProvider, question: &str) -> String {
+        let stream = provider
+            .chat_stream(vec![Message::user(question)])
+            .await
+            .unwrap();
+        Completion::from_stream(stream).await.unwrap().text
+    }
+
+    #[tokio::test]
+    async fn test_repeated_question_is_replayed() {
+        let dir = TempDir::new().unwrap();
+        let provider = CacheProvider::new(
+            CountingProvider::default(),
+            cache(dir.path()),
+            &config(0.0),
+            false,
+        );
+
+        assert_eq!(text(&provider, "list files").await, "Run ```ls```");
+        assert_eq!(text(&provider, "list files").await, "Run ```ls```");
+        assert_eq!(provider.inner.calls.load(Ordering::SeqCst), 1);
+
+        let completion = provider
+            .complete(vec![Message::user("list files")])
+            .await
+            .unwrap();
+        assert_eq!(completion.stop_reason, Some(StopReason::EndTurn));
+        assert_eq!(provider.inner.calls.load(Ordering::SeqCst), 1);
+
+        text(&provider, "list all files").await;
+        assert_eq!(provider.inner.calls.load(Ordering::SeqCst), 2);
+
+        // Other settings shaping the answer use other entries, the API key does not
+        let other = CacheProvider::new(
+            CountingProvider::default(),
+            cache(dir.path()),
+            &config(1.0),
+            false,
+        );
+        text(&other, "list files").await;
+        assert_eq!(other.inner.calls.load(Ordering::SeqCst), 1);
+        let same = CacheProvider::new(
+            CountingProvider::default(),
+            cache(dir.path()),
+            &LLMConfig {
+                api_key: "other secret".to_string(),
+                ..config(0.0)
+            },
+            false,
+        );
+        text(&same, "list files").await;
+        assert_eq!(same.inner.calls.load(Ordering::SeqCst), 0);
+
+        assert_eq!(cache(dir.path()).clear().unwrap(), 3);
+        assert_eq!(cache(dir.path()).clear().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_are_not_stored() {
+        let dir = TempDir::new().unwrap();
+        let inner = CountingProvider {
+            tool_call: true,
+            ..CountingProvider::default()
+        };
+        let provider = CacheProvider::new(inner, cache(dir.path()), &config(0.0), false);
+
+        text(&provider, "read Cargo.toml").await;
+        text(&provider, "read Cargo.toml").await;
+        assert_eq!(provider.inner.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache(dir.path()).clear().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answers_are_not_stored() {
+        let dir = TempDir::new().unwrap();
+        let inner = CountingProvider {
+            truncated: true,
+            ..CountingProvider::default()
+        };
+        let provider = CacheProvider::new(inner, cache(dir.path()), &config(0.0), false);
+
+        text(&provider, "list files").await;
+        text(&provider, "list files").await;
+        assert_eq!(provider.inner.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache(dir.path()).clear().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_expired_and_excess_answers_are_removed() {
+        let dir = TempDir::new().unwrap();
+        let completion = Completion {
+            text: "x".repeat(100),
+            ..Completion::default()
+        };
+
+        let expired = ResponseCache::new(CacheConfig {
+            ttl: Duration::ZERO,
+            ..cache(dir.path()).config
+        });
+        expired.put("a", &completion).unwrap();
+        assert!(expired.get("a").is_none());
+
+        let small = ResponseCache::new(CacheConfig {
+            max_bytes: 300, // Room for two answers
+            ..cache(dir.path()).config
+        });
+        for key in ["b", "c", "d"] {
+            small.put(key, &completion).unwrap();
+            std::thread::sleep(Duration::from_millis(10)); // Distinct modification times
+        }
+        assert_eq!(small.entry_paths().unwrap().len(), 2);
+        assert!(small.get("d").is_some());
+    }
+}
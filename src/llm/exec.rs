@@ -0,0 +1,378 @@
+//! Provider delegating to an external command over stdio
+//!
+//! The request is written to the command's stdin as one JSON object, then stdin is closed:
+//!
+//! ```json
+//! {"model": "...", "messages": [{"role": "system", "content": "..."}], "parameters": {"temperature": 0.0}}
+//! ```
+//!
+//! The command answers with one JSON object per line on stdout:
+//!
+//! - `{"type": "text", "text": "..."}`
+//! - `{"type": "reasoning", "text": "..."}`, shown apart from the answer
+//! - `{"type": "usage", "input_tokens": 12, "output_tokens": 34}`
+//! - `{"type": "stop", "reason": "end_turn"}`
+//! - `{"type": "metadata", "id": "...", "model": "..."}`
+//! - `{"type": "error", "class": "rate_limit", "message": "..."}` with a class from `LLMError::class`
+//!
+//! Its stderr is passed through to the user.
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdout, Command};
+
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::{
+    ChatStream, GenerationParams, LLMConfig, LLMError, LLMProvider, Message, StopReason,
+    StreamEvent, Usage,
+};
+
+const ENV_EXEC_COMMAND: &str = "ASK_SH_EXEC_COMMAND";
+const ENV_EXEC_MODEL: &str = "ASK_SH_EXEC_MODEL";
+
+#[derive(Debug)]
+pub struct ExecProvider {
+    model: String,
+    command: String, // Run through `sh -c`
+    generation: GenerationParams,
+}
+
+#[derive(Serialize, Debug)]
+struct ExecRequest<'a> {
+    model: &'a str,
+    messages: Vec<ExecMessage<'a>>,
+    parameters: Parameters<'a>,
+}
+
+#[derive(Serialize, Debug)]
+struct ExecMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct Parameters<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_budget: Option<u32>,
+}
+
+/// One line of the command's output
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExecEvent {
+    Text {
+        text: String,
+    },
+    Reasoning {
+        text: String,
+    },
+    Usage {
+        input_tokens: Option<u32>,
+        output_tokens: Option<u32>,
+    },
+    Stop {
+        reason: String,
+    },
+    Metadata {
+        id: Option<String>,
+        model: Option<String>,
+    },
+    Error {
+        class: Option<String>,
+        message: String,
+    },
+}
+
+/// State of the output stream
+struct Output {
+    lines: tokio::io::Lines<BufReader<ChildStdout>>,
+    child: Child,
+    failed: bool, // An error has been reported, so the exit status adds nothing
+}
+
+impl ExecProvider {
+    pub fn new(config: LLMConfig) -> Result<Self, LLMError> {
+        let command = config
+            .command
+            .filter(|command| !command.trim().is_empty())
+            .ok_or_else(|| {
+                LLMError::ConfigError("Command for exec provider not set".to_string())
+            })?;
+
+        Ok(Self {
+            model: config.model,
+            command,
+            generation: config.generation,
+        })
+    }
+
+    fn create_request<'a>(&'a self, messages: &'a [Message]) -> ExecRequest<'a> {
+        ExecRequest {
+            model: &self.model,
+            messages: messages
+                .iter()
+                .map(|message| ExecMessage {
+                    role: message.role.as_str(),
+                    content: &message.content,
+                })
+                .collect(),
+            parameters: Parameters {
+                max_tokens: self.generation.max_tokens,
+                temperature: self.generation.temperature,
+                top_p: self.generation.top_p,
+                stop: &self.generation.stop,
+                seed: self.generation.seed,
+                reasoning_effort: self.generation.reasoning_effort.as_deref(),
+                thinking_budget: self.generation.thinking_budget,
+            },
+        }
+    }
+
+    fn parse_line(line: &str) -> Option<Result<StreamEvent, LLMError>> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let event = match serde_json::from_str::<ExecEvent>(line) {
+            Ok(event) => event,
+            Err(e) => {
+                return Some(Err(LLMError::ApiError(format!(
+                    "Invalid output from command: {}",
+                    e
+                ))))
+            }
+        };
+        Some(match event {
+            ExecEvent::Text { text } => Ok(StreamEvent::TextDelta(text)),
+            ExecEvent::Reasoning { text } => Ok(StreamEvent::ReasoningDelta(text)),
+            ExecEvent::Usage {
+                input_tokens,
+                output_tokens,
+            } => Ok(StreamEvent::Usage(Usage {
+                input_tokens,
+                output_tokens,
+                ..Default::default()
+            })),
+            ExecEvent::Stop { reason } => Ok(StreamEvent::Stop(StopReason::from_provider(&reason))),
+            ExecEvent::Metadata { id, model } => Ok(StreamEvent::Metadata { id, model }),
+            ExecEvent::Error { class, message } => Err(class
+                .and_then(|class| LLMError::from_class(&class, message.clone()))
+                .unwrap_or(LLMError::ApiError(message))),
+        })
+    }
+
+    /// Next event, and whether more may follow
+    async fn next_event(output: &mut Output) -> Option<(Result<StreamEvent, LLMError>, bool)> {
+        loop {
+            match output.lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Some(event) = Self::parse_line(&line) {
+                        output.failed |= event.is_err();
+                        return Some((event, true));
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => return Some((Err(LLMError::StreamInterrupted(e.to_string())), false)),
+            }
+        }
+
+        // The output is complete, so a failure can only be told by the exit status
+        match output.child.wait().await {
+            Ok(status) if !status.success() && !output.failed => Some((
+                Err(LLMError::ApiError(format!(
+                    "Command exited with {}",
+                    status
+                ))),
+                false,
+            )),
+            Ok(_) => None,
+            Err(e) => Some((Err(LLMError::ApiError(e.to_string())), false)),
+        }
+    }
+}
+
+#[async_trait]
+impl LLMProvider for ExecProvider {
+    fn name(&self) -> &'static str {
+        "exec"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let mut request = serde_json::to_vec(&self.create_request(&messages))
+            .map_err(|e| LLMError::ApiError(e.to_string()))?;
+        request.push(b'\n');
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true) // Stop the command when the answer is abandoned
+            .spawn()
+            .map_err(|e| LLMError::ConfigError(format!("Cannot run `{}`: {}", self.command, e)))?;
+
+        // Write from a separate task, as the command may start answering before reading all
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        tokio::spawn(async move {
+            // A command that does not read its input is not an error
+            let _ = stdin.write_all(&request).await;
+        });
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let output = Output {
+            lines: BufReader::new(stdout).lines(),
+            child,
+            failed: false,
+        };
+        Ok(Box::pin(
+            stream::unfold(Some(output), |output| async move {
+                let mut output = output?;
+                let (event, more) = Self::next_event(&mut output).await?;
+                Some((event, more.then_some(output)))
+            })
+            .fuse(),
+        ))
+    }
+}
+
+pub struct ExecFactory;
+
+impl ProviderFactory for ExecFactory {
+    fn name(&self) -> &'static str {
+        "exec"
+    }
+
+    // The command takes care of endpoints and authentication itself
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::model(ENV_EXEC_MODEL).with_default("default"),
+            Setting::endpoint(ENV_EXEC_COMMAND),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        Ok(LLMConfig {
+            model: settings.require(ENV_EXEC_MODEL)?,
+            command: Some(settings.require(ENV_EXEC_COMMAND)?),
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(ExecProvider::new(config)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn exec_provider(command: &str) -> ExecProvider {
+        ExecProvider::new(LLMConfig {
+            provider: "exec".to_string(),
+            model: "gateway-model".to_string(),
+            command: Some(command.to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    async fn collect(provider: &ExecProvider) -> Vec<Result<StreamEvent, LLMError>> {
+        let stream = provider
+            .chat_stream(vec![Message::system("be brief"), Message::user("hi")])
+            .await
+            .unwrap();
+        stream.collect().await
+    }
+
+    #[tokio::test]
+    async fn test_exec_streams_json_lines() {
+        let dir = TempDir::new().unwrap();
+        let request_file = dir.path().join("request.json");
+        let provider = exec_provider(&format!(
+            r#"cat > '{}'
+            echo '{{"type": "metadata", "model": "gateway-model-v2"}}'
+            echo '{{"type": "text", "text": "Hel"}}'
+            echo
+            echo '{{"type": "text", "text": "lo"}}'
+            echo '{{"type": "stop", "reason": "end_turn"}}'
+            echo '{{"type": "usage", "input_tokens": 5, "output_tokens": 2}}'"#,
+            request_file.display()
+        ));
+
+        let events: Vec<StreamEvent> = collect(&provider)
+            .await
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Metadata {
+                    id: None,
+                    model: Some("gateway-model-v2".to_string()),
+                },
+                StreamEvent::TextDelta("Hel".to_string()),
+                StreamEvent::TextDelta("lo".to_string()),
+                StreamEvent::Stop(StopReason::EndTurn),
+                StreamEvent::Usage(Usage {
+                    input_tokens: Some(5),
+                    output_tokens: Some(2),
+                    ..Default::default()
+                }),
+            ]
+        );
+
+        let request: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&request_file).unwrap()).unwrap();
+        assert_eq!(request["model"], "gateway-model");
+        assert_eq!(request["messages"][0]["role"], "system");
+        assert_eq!(request["messages"][1]["content"], "hi");
+        assert_eq!(request["parameters"], serde_json::json!({}));
+    }
+
+    #[tokio::test]
+    async fn test_exec_errors_are_mapped() {
+        let provider = exec_provider(
+            r#"echo '{"type": "error", "class": "rate_limit", "message": "slow down"}'; exit 1"#,
+        );
+        let events = collect(&provider).await;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], Err(LLMError::RateLimit { .. })));
+
+        let provider = exec_provider(r#"echo '{"type": "text", "text": "partial"}'; exit 3"#);
+        let events = collect(&provider).await;
+        assert_eq!(events.len(), 2);
+        let err = events[1].as_ref().unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"));
+
+        let provider = exec_provider("echo not json");
+        let events = collect(&provider).await;
+        assert!(matches!(events[0], Err(LLMError::ApiError(_))));
+    }
+}
@@ -0,0 +1,455 @@
+//! Composite provider that tries an ordered list of providers
+//!
+//! The next provider is only tried until the first piece of text is received, so the
+//! answers of two providers are never mixed. Without streaming, it is tried until one
+//! provider returns a whole answer.
+
+use async_trait::async_trait;
+
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::retry::wait_for_text;
+use super::{
+    ChatStream, Completion, LLMConfig, LLMError, LLMProvider, Message, ModelInfo,
+    DEFAULT_FALLBACK_ON, ERROR_CLASSES,
+};
+
+const ENV_FALLBACK_PROVIDERS: &str = "ASK_SH_FALLBACK_PROVIDERS";
+const ENV_FALLBACK_ON: &str = "ASK_SH_FALLBACK_ON";
+
+#[derive(Debug)]
+pub struct FallbackProvider {
+    providers: Vec<Box<dyn LLMProvider>>,
+    fallback_on: Vec<String>, // Error classes, see `LLMError::class`
+    chain: String,            // e.g. "anthropic/claude-3-5-sonnet-latest, ollama/llama3.2"
+    debug: bool,              // Report failed providers and the one that answered to stderr
+}
+
+impl FallbackProvider {
+    pub fn new(config: LLMConfig, registry: &Registry) -> Result<Self, LLMError> {
+        let providers = config
+            .fallback
+            .into_iter()
+            .map(|member| registry.create(member))
+            .collect::<Result<Vec<_>, LLMError>>()?;
+        Self::with_providers(providers, config.fallback_on, config.debug)
+    }
+
+    /// Chain of providers built by the caller, e.g. each wrapped with retries
+    pub fn with_providers(
+        providers: Vec<Box<dyn LLMProvider>>,
+        fallback_on: Vec<String>,
+        debug: bool,
+    ) -> Result<Self, LLMError> {
+        if providers.is_empty() {
+            return Err(LLMError::ConfigError(
+                "The fallback chain has no providers".to_string(),
+            ));
+        }
+        let chain = providers
+            .iter()
+            .map(|p| format!("{}/{}", p.name(), p.model()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok(Self {
+            providers,
+            fallback_on,
+            chain,
+            debug,
+        })
+    }
+
+    /// Whether to try the next provider after this one failed
+    fn falls_through(&self, provider: &dyn LLMProvider, error: &LLMError) -> bool {
+        let falls_through = self.fallback_on.iter().any(|c| c == error.class());
+        if falls_through && self.debug {
+            eprintln!(
+                "fallback: {}/{} failed, trying the next provider: {}",
+                provider.name(),
+                provider.model(),
+                error
+            );
+        }
+        falls_through
+    }
+
+    fn report_answer(&self, provider: &dyn LLMProvider) {
+        if self.debug {
+            eprintln!("answered_by: {}/{}", provider.name(), provider.model());
+        }
+    }
+}
+
+#[async_trait]
+impl LLMProvider for FallbackProvider {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    fn model(&self) -> &str {
+        &self.chain
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let (last, rest) = self.providers.split_last().expect("checked in new()");
+        for provider in rest {
+            let result = match provider.chat_stream(messages.clone()).await {
+                Ok(stream) => wait_for_text(stream).await,
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(stream) => {
+                    self.report_answer(provider.as_ref());
+                    return Ok(stream);
+                }
+                Err(error) if self.falls_through(provider.as_ref(), &error) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        let stream = wait_for_text(last.chat_stream(messages).await?).await?;
+        self.report_answer(last.as_ref());
+        Ok(stream)
+    }
+
+    async fn complete(&self, messages: Vec<Message>) -> Result<Completion, LLMError> {
+        let (last, rest) = self.providers.split_last().expect("checked in new()");
+        for provider in rest {
+            match provider.complete(messages.clone()).await {
+                Ok(completion) => {
+                    self.report_answer(provider.as_ref());
+                    return Ok(completion);
+                }
+                Err(error) if self.falls_through(provider.as_ref(), &error) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        let completion = last.complete(messages).await?;
+        self.report_answer(last.as_ref());
+        Ok(completion)
+    }
+
+    /// Models of all members, named as in the chain, e.g. `openai/gpt-4o-mini`.
+    /// Members that cannot list their models are left out.
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        let mut models = Vec::new();
+        let mut last_error = None;
+        for provider in &self.providers {
+            match provider.list_models().await {
+                Ok(listed) => models.extend(listed.into_iter().map(|model| ModelInfo {
+                    id: format!("{}/{}", provider.name(), model.id),
+                    ..model
+                })),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        match last_error {
+            Some(error) if models.is_empty() => Err(error),
+            _ => Ok(models),
+        }
+    }
+
+    /// Any provider of the chain may answer, so all of them must take images
+    fn supports_images(&self) -> bool {
+        self.providers.iter().all(|p| p.supports_images())
+    }
+}
+
+pub struct FallbackFactory;
+
+impl ProviderFactory for FallbackFactory {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            // e.g. `anthropic,openai/gpt-4o-mini,ollama`
+            Setting::model(ENV_FALLBACK_PROVIDERS),
+            Setting::other(ENV_FALLBACK_ON),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        let chain = settings.get(ENV_FALLBACK_PROVIDERS).ok_or_else(|| {
+            LLMError::ConfigError(format!(
+                "{} must list the providers to try",
+                ENV_FALLBACK_PROVIDERS
+            ))
+        })?;
+
+        let fallback = chain
+            .split(',')
+            .map(str::trim)
+            .filter(|member| !member.is_empty())
+            .map(|member| {
+                if member.split('/').next() == Some(self.name()) {
+                    return Err(LLMError::ConfigError(
+                        "A fallback chain cannot contain another one".to_string(),
+                    ));
+                }
+                settings.provider_config(member)
+            })
+            .collect::<Result<Vec<_>, LLMError>>()?;
+
+        let fallback_on = match settings.get(ENV_FALLBACK_ON) {
+            Some(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|class| !class.is_empty())
+                .map(|class| {
+                    if !ERROR_CLASSES.contains(&class) {
+                        return Err(LLMError::ConfigError(format!(
+                            "Unknown error class in {}: {}. Valid classes are {}",
+                            ENV_FALLBACK_ON,
+                            class,
+                            ERROR_CLASSES.join(", ")
+                        )));
+                    }
+                    Ok(class.to_string())
+                })
+                .collect::<Result<_, LLMError>>()?,
+            None => DEFAULT_FALLBACK_ON
+                .iter()
+                .map(|class| class.to_string())
+                .collect(),
+        };
+
+        Ok(LLMConfig {
+            fallback,
+            fallback_on,
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        config: LLMConfig,
+        registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(FallbackProvider::new(config, registry)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::StreamEvent;
+    use futures::stream::{self, StreamExt};
+
+    /// Provider that always returns the same result
+    #[derive(Debug)]
+    struct FixedProvider {
+        model: &'static str,
+        error: Option<fn() -> LLMError>, // Error returned before any text
+    }
+
+    impl FixedProvider {
+        fn answering(model: &'static str) -> Box<Self> {
+            Box::new(Self { model, error: None })
+        }
+
+        fn failing(model: &'static str, error: fn() -> LLMError) -> Box<Self> {
+            Box::new(Self {
+                model,
+                error: Some(error),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl LLMProvider for FixedProvider {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn model(&self) -> &str {
+            self.model
+        }
+
+        async fn chat_stream(&self, _messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+            let first = match self.error {
+                // Errors may also arrive as the first event of the stream
+                Some(error) => Err(error()),
+                None => Ok(StreamEvent::TextDelta(format!("from {}", self.model))),
+            };
+            Ok(Box::pin(stream::iter(vec![first])))
+        }
+
+        async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+            match self.error {
+                Some(error) => Err(error()),
+                None => Ok(vec![ModelInfo {
+                    id: self.model.to_string(),
+                    context_length: Some(8_192),
+                }]),
+            }
+        }
+    }
+
+    fn fallback_on() -> Vec<String> {
+        DEFAULT_FALLBACK_ON.iter().map(|c| c.to_string()).collect()
+    }
+
+    async fn first_text(provider: &FallbackProvider) -> Result<String, LLMError> {
+        let mut stream = provider.chat_stream(vec![Message::user("hi")]).await?;
+        match stream.next().await {
+            Some(Ok(StreamEvent::TextDelta(text))) => Ok(text),
+            other => panic!("unexpected event: {:?}", other.map(|r| r.ok())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_through_on_configured_errors() {
+        let provider = FallbackProvider::with_providers(
+            vec![
+                FixedProvider::failing("primary", || LLMError::Overloaded {
+                    message: "Overloaded".to_string(),
+                    retry_after: None,
+                }),
+                FixedProvider::failing("secondary", || {
+                    LLMError::NetworkError("connection refused".to_string())
+                }),
+                FixedProvider::answering("local"),
+            ],
+            fallback_on(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            provider.model(),
+            "fixed/primary, fixed/secondary, fixed/local"
+        );
+
+        assert_eq!(first_text(&provider).await.unwrap(), "from local");
+    }
+
+    #[tokio::test]
+    async fn test_stops_on_other_errors() {
+        let provider = FallbackProvider::with_providers(
+            vec![
+                FixedProvider::failing("primary", || {
+                    LLMError::ContextTooLong("prompt is too long".to_string())
+                }),
+                FixedProvider::answering("backup"),
+            ],
+            fallback_on(),
+            false,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            first_text(&provider).await,
+            Err(LLMError::ContextTooLong(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_returns_error_of_last_provider() {
+        let provider = FallbackProvider::with_providers(
+            vec![
+                FixedProvider::failing("primary", || LLMError::RateLimit {
+                    message: "slow down".to_string(),
+                    retry_after: None,
+                }),
+                FixedProvider::failing("backup", || {
+                    LLMError::Authentication("invalid key".to_string())
+                }),
+            ],
+            vec!["rate_limit".to_string()],
+            false,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            first_text(&provider).await,
+            Err(LLMError::Authentication(_))
+        ));
+    }
+
+    #[test]
+    fn test_empty_chain_is_rejected() {
+        assert!(matches!(
+            FallbackProvider::with_providers(Vec::new(), fallback_on(), false),
+            Err(LLMError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_complete_falls_through() {
+        let provider = FallbackProvider::with_providers(
+            vec![
+                FixedProvider::failing("primary", || {
+                    LLMError::NetworkError("connection refused".to_string())
+                }),
+                FixedProvider::answering("local"),
+            ],
+            fallback_on(),
+            false,
+        )
+        .unwrap();
+
+        let completion = provider.complete(vec![Message::user("hi")]).await.unwrap();
+        assert_eq!(completion.text, "from local");
+    }
+
+    #[tokio::test]
+    async fn test_models_of_all_members_are_listed() {
+        let provider = FallbackProvider::with_providers(
+            vec![
+                FixedProvider::answering("primary"),
+                FixedProvider::failing("offline", || {
+                    LLMError::NetworkError("connection refused".to_string())
+                }),
+                FixedProvider::answering("local"),
+            ],
+            fallback_on(),
+            false,
+        )
+        .unwrap();
+
+        let models = provider.list_models().await.unwrap();
+        let ids: Vec<&str> = models.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["fixed/primary", "fixed/local"]);
+        assert_eq!(models[0].context_length, Some(8_192));
+
+        let offline = FallbackProvider::with_providers(
+            vec![FixedProvider::failing("offline", || {
+                LLMError::NetworkError("connection refused".to_string())
+            })],
+            fallback_on(),
+            false,
+        )
+        .unwrap();
+        assert!(matches!(
+            offline.list_models().await,
+            Err(LLMError::NetworkError(_))
+        ));
+    }
+
+    #[test]
+    fn test_error_classes_are_validated() {
+        for class in ERROR_CLASSES {
+            let error = LLMError::from_class(class, String::new()).unwrap();
+            assert_eq!(error.class(), *class);
+        }
+
+        let source = |key: &str| match key {
+            "ASK_SH_FALLBACK_PROVIDERS" => Some("ollama".to_string()),
+            "ASK_SH_FALLBACK_ON" => Some("network, ratelimit".to_string()),
+            _ => None,
+        };
+        let error = Registry::builtin()
+            .configure("fallback", &source)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            LLMError::ConfigError(format!(
+                "Unknown error class in ASK_SH_FALLBACK_ON: ratelimit. Valid classes are {}",
+                ERROR_CLASSES.join(", ")
+            ))
+            .to_string()
+        );
+    }
+}
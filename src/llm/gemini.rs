@@ -0,0 +1,529 @@
+use async_trait::async_trait;
+use futures::stream::{Stream, StreamExt};
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::time::Duration;
+
+use super::http;
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::retry::retry_after;
+use super::sse::{sse_stream, SseEvent};
+use super::{
+    ChatStream, GenerationParams, LLMConfig, LLMError, LLMProvider, Message, Role, StopReason,
+    StreamEvent, Usage,
+};
+
+pub const GEMINI_DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com";
+
+const ENV_GEMINI_API_KEY: &str = "ASK_SH_GEMINI_API_KEY";
+const ENV_GEMINI_MODEL: &str = "ASK_SH_GEMINI_MODEL";
+const ENV_GEMINI_BASE_URL: &str = "ASK_SH_GEMINI_BASE_URL";
+
+#[derive(Debug)]
+pub struct GeminiProvider {
+    client: Client,
+    read_timeout: Option<Duration>,
+    model: String,
+    api_key: String,
+    base_url: String,
+    generation: GenerationParams,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Content {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>, // "user" or "model", none for the system instruction
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Part {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+/// One chunk of the streamed GenerateContentResponse
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiStreamChunk {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    usage_metadata: Option<UsageMetadata>,
+    model_version: Option<String>,
+    response_id: Option<String>,
+    prompt_feedback: Option<PromptFeedback>,
+    error: Option<GeminiError>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<Content>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    prompt_token_count: Option<u32>,
+    candidates_token_count: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>, // Set when the prompt was rejected, e.g. SAFETY
+}
+
+#[derive(Deserialize, Debug)]
+struct GeminiError {
+    message: String,
+    status: Option<String>, // Canonical status, e.g. RESOURCE_EXHAUSTED
+}
+
+impl GeminiError {
+    /// Translate the canonical status into the error kinds understood by `LLMError`
+    fn kind(&self) -> Option<&str> {
+        // An invalid key is reported as INVALID_ARGUMENT (HTTP 400)
+        if self.message.contains("API key not valid") {
+            return Some("invalid_api_key");
+        }
+        match self.status.as_deref()? {
+            "UNAUTHENTICATED" | "PERMISSION_DENIED" => Some("authentication_error"),
+            "NOT_FOUND" => Some("not_found_error"),
+            "RESOURCE_EXHAUSTED" => Some("rate_limit_exceeded"),
+            "UNAVAILABLE" => Some("overloaded_error"),
+            "DEADLINE_EXCEEDED" => Some("timeout"),
+            _ => None,
+        }
+    }
+}
+
+/// Body of a non-successful response
+#[derive(Deserialize, Debug)]
+struct GeminiErrorResponse {
+    error: GeminiError,
+}
+
+impl GeminiProvider {
+    pub fn new(config: LLMConfig) -> Result<Self, LLMError> {
+        let client = config.http.client()?;
+
+        let base_url = config
+            .base_url
+            .unwrap_or_else(|| GEMINI_DEFAULT_BASE_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        Ok(Self {
+            client,
+            read_timeout: config.http.read_timeout,
+            model: config.model,
+            api_key: config.api_key,
+            base_url,
+            generation: config.generation,
+        })
+    }
+
+    fn create_request(&self, messages: &[Message]) -> GeminiRequest {
+        // Gemini takes the system prompt as a separate instruction
+        let system_parts: Vec<Part> = messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(|message| Part {
+                text: message.content.clone(),
+            })
+            .collect();
+        let system_instruction = if system_parts.is_empty() {
+            None
+        } else {
+            Some(Content {
+                role: None,
+                parts: system_parts,
+            })
+        };
+
+        // Consecutive messages of the same role become parts of one content
+        let mut contents: Vec<Content> = Vec::new();
+        for message in messages.iter().filter(|m| m.role != Role::System) {
+            let role = match message.role {
+                Role::Assistant => "model",
+                _ => "user",
+            };
+            let part = Part {
+                text: message.content.clone(),
+            };
+            match contents.last_mut() {
+                Some(last) if last.role.as_deref() == Some(role) => last.parts.push(part),
+                _ => contents.push(Content {
+                    role: Some(role.to_string()),
+                    parts: vec![part],
+                }),
+            }
+        }
+
+        let generation = &self.generation;
+        let generation_config = if *generation == GenerationParams::default() {
+            None
+        } else {
+            Some(GenerationConfig {
+                max_output_tokens: generation.max_tokens,
+                temperature: generation.temperature,
+                top_p: generation.top_p,
+                stop_sequences: generation.stop.clone(),
+                seed: generation.seed,
+            })
+        };
+
+        GeminiRequest {
+            contents,
+            system_instruction,
+            generation_config,
+        }
+    }
+
+    /// Parse one chunk. `metadata_sent` is set once the response metadata has been emitted,
+    /// as every chunk repeats it.
+    fn parse_event(
+        event: &SseEvent,
+        metadata_sent: &mut bool,
+    ) -> Vec<Result<StreamEvent, LLMError>> {
+        let chunk = match serde_json::from_str::<GeminiStreamChunk>(&event.data) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return vec![Err(LLMError::ApiError(format!(
+                    "Invalid response from Gemini: {}",
+                    e
+                )))]
+            }
+        };
+        if let Some(error) = chunk.error {
+            return vec![Err(LLMError::from_api_error(
+                None,
+                error.kind(),
+                error.message.clone(),
+                None,
+            ))];
+        }
+        if let Some(reason) = chunk.prompt_feedback.and_then(|f| f.block_reason) {
+            return vec![Err(LLMError::ApiError(format!(
+                "The prompt was blocked by Gemini ({})",
+                reason
+            )))];
+        }
+
+        let mut events = Vec::new();
+        if !*metadata_sent && (chunk.response_id.is_some() || chunk.model_version.is_some()) {
+            *metadata_sent = true;
+            events.push(Ok(StreamEvent::Metadata {
+                id: chunk.response_id,
+                model: chunk.model_version,
+            }));
+        }
+
+        // Only the first candidate is used, as candidateCount is never set
+        if let Some(candidate) = chunk.candidates.into_iter().next() {
+            let text: String = candidate
+                .content
+                .map(|content| content.parts.into_iter().map(|part| part.text).collect())
+                .unwrap_or_default();
+            if !text.is_empty() {
+                events.push(Ok(StreamEvent::TextDelta(text)));
+            }
+            if let Some(reason) = candidate.finish_reason {
+                // e.g. STOP, MAX_TOKENS or SAFETY
+                let reason = reason.to_lowercase();
+                events.push(Ok(StreamEvent::Stop(StopReason::from_provider(&reason))));
+            }
+        }
+        if let Some(usage) = chunk.usage_metadata {
+            events.push(Ok(StreamEvent::Usage(Usage {
+                input_tokens: usage.prompt_token_count,
+                output_tokens: usage.candidates_token_count,
+                ..Default::default()
+            })));
+        }
+        events
+    }
+
+    /// Convert the raw response body into a stream of events
+    fn event_stream<S, B>(bytes: S) -> impl Stream<Item = Result<StreamEvent, LLMError>>
+    where
+        S: Stream<Item = Result<B, reqwest::Error>> + Unpin,
+        B: AsRef<[u8]>,
+    {
+        sse_stream(bytes)
+            .scan(false, |metadata_sent, result| {
+                futures::future::ready(Some(match result {
+                    Ok(event) => Self::parse_event(&event, metadata_sent),
+                    Err(e) => vec![Err(LLMError::StreamInterrupted(e.to_string()))],
+                }))
+            })
+            .flat_map(futures::stream::iter)
+    }
+}
+
+#[async_trait]
+impl LLMProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let request = self.create_request(&messages);
+
+        // alt=sse switches the response from a JSON array to server-sent events
+        let mut request_builder = self
+            .client
+            .post(format!(
+                "{}/v1beta/models/{}:streamGenerateContent?alt=sse",
+                self.base_url, self.model
+            ))
+            .header(header::CONTENT_TYPE, "application/json");
+        if !self.api_key.is_empty() {
+            request_builder = request_builder.header("x-goog-api-key", &self.api_key);
+        }
+
+        let response = http::send(request_builder.json(&request), self.read_timeout).await?;
+
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let retry_after = retry_after(response.headers());
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(
+                match serde_json::from_str::<GeminiErrorResponse>(&error_text) {
+                    Ok(body) => LLMError::from_api_error(
+                        Some(status),
+                        body.error.kind(),
+                        body.error.message.clone(),
+                        retry_after,
+                    ),
+                    Err(_) => LLMError::from_api_error(Some(status), None, error_text, retry_after),
+                },
+            );
+        }
+
+        Ok(http::with_read_timeout(
+            Box::pin(Self::event_stream(response.bytes_stream())),
+            self.read_timeout,
+        ))
+    }
+}
+
+pub struct GeminiFactory;
+
+impl ProviderFactory for GeminiFactory {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::api_key(ENV_GEMINI_API_KEY),
+            Setting::model(ENV_GEMINI_MODEL).with_default("gemini-2.0-flash"),
+            Setting::endpoint(ENV_GEMINI_BASE_URL),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        let api_key = settings
+            .get(ENV_GEMINI_API_KEY)
+            .ok_or_else(|| LLMError::ConfigError("Gemini API key not found".to_string()))?;
+
+        Ok(LLMConfig {
+            api_key,
+            model: settings.require(ENV_GEMINI_MODEL)?,
+            base_url: settings.get(ENV_GEMINI_BASE_URL),
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(GeminiProvider::new(config)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::{self, StubResponse};
+
+    fn test_config(base_url: Option<String>) -> LLMConfig {
+        LLMConfig {
+            provider: "gemini".to_string(),
+            model: "gemini-2.0-flash".to_string(),
+            api_key: "test-key".to_string(),
+            base_url,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_gemini_request_maps_system_and_roles() {
+        let provider = GeminiProvider::new(test_config(None)).unwrap();
+        assert_eq!(provider.name(), "gemini");
+        assert_eq!(provider.base_url, GEMINI_DEFAULT_BASE_URL);
+
+        let request = provider.create_request(&[
+            Message::system("be brief"),
+            Message::user("what is ls"),
+            Message::assistant("it lists files"),
+            Message::user("and ls -a?"),
+            Message::user("and ls -l?"),
+        ]);
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            body["systemInstruction"],
+            serde_json::json!({"parts": [{"text": "be brief"}]})
+        );
+        let contents = body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[0]["role"], "user");
+        assert_eq!(contents[1]["role"], "model");
+        assert_eq!(contents[2]["parts"].as_array().unwrap().len(), 2);
+        assert!(body.get("generationConfig").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_gemini_chat_stream_against_stub() {
+        // Recorded from streamGenerateContent?alt=sse, split at arbitrary points
+        let response = StubResponse::new(200, "text/event-stream", "").with_chunks(vec![
+            "data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"List files with\"}],\"role\": \"model\"}}],\"usageMetadata\": {\"promptTokenCount\": 12,\"totalTokenCount\": 12},\"modelVersion\": \"gemini-2.0-flash\",\"responseId\": \"resp_1\"}\r\n\r\nda",
+            "ta: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \" `ls -la`\"}],\"role\": \"model\"},\"finishReason\": \"STOP\"}],\"usageMetadata\": {\"promptTokenCount\": 12,\"candidatesTokenCount\": 6,\"totalTokenCount\": 18},\"modelVersion\": \"gemini-2.0-flash\",\"responseId\": \"resp_1\"}\r\n\r\n",
+        ]);
+        let (base_url, server) = stub_server::serve(vec![response]).await;
+
+        let mut config = test_config(Some(base_url));
+        config.generation.max_tokens = Some(256);
+        config.generation.stop = vec!["END".to_string()];
+        let provider = GeminiProvider::new(config).unwrap();
+
+        let stream = provider
+            .chat_stream(vec![Message::system("system"), Message::user("hi")])
+            .await
+            .unwrap();
+        let events: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Metadata {
+                    id: Some("resp_1".to_string()),
+                    model: Some("gemini-2.0-flash".to_string()),
+                },
+                StreamEvent::TextDelta("List files with".to_string()),
+                StreamEvent::Usage(Usage {
+                    input_tokens: Some(12),
+                    output_tokens: None,
+                    ..Default::default()
+                }),
+                StreamEvent::TextDelta(" `ls -la`".to_string()),
+                StreamEvent::Stop(StopReason::EndTurn),
+                StreamEvent::Usage(Usage {
+                    input_tokens: Some(12),
+                    output_tokens: Some(6),
+                    ..Default::default()
+                }),
+            ]
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /v1beta/models/gemini-2.0-flash:streamGenerateContent?alt=sse HTTP/1.1"
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("test-key"));
+        let body = requests[0].json();
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "system");
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "hi");
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 256);
+        assert_eq!(
+            body["generationConfig"]["stopSequences"],
+            serde_json::json!(["END"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gemini_error_responses_are_classified() {
+        let invalid_key = StubResponse::new(
+            400,
+            "application/json",
+            "{\"error\": {\"code\": 400,\"message\": \"API key not valid. Please pass a valid API key.\",\"status\": \"INVALID_ARGUMENT\"}}",
+        );
+        let exhausted = StubResponse::new(
+            429,
+            "application/json",
+            "{\"error\": {\"code\": 429,\"message\": \"Resource has been exhausted (e.g. check quota).\",\"status\": \"RESOURCE_EXHAUSTED\"}}",
+        )
+        .with_header("Retry-After", "2");
+        let (base_url, _server) = stub_server::serve(vec![invalid_key, exhausted]).await;
+        let provider = GeminiProvider::new(test_config(Some(base_url))).unwrap();
+
+        let Err(err) = provider.chat_stream(vec![Message::user("hi")]).await else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, LLMError::Authentication(_)));
+
+        let Err(err) = provider.chat_stream(vec![Message::user("hi")]).await else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, LLMError::RateLimit { .. }));
+        assert_eq!(err.retry_after(), Some(std::time::Duration::from_secs(2)));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_prompt_is_an_error() {
+        let body = "data: {\"promptFeedback\": {\"blockReason\": \"SAFETY\"},\"modelVersion\": \"gemini-2.0-flash\"}\n\n";
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let results: Vec<Result<StreamEvent, LLMError>> =
+            GeminiProvider::event_stream(futures::stream::iter(chunks))
+                .collect()
+                .await;
+        assert_eq!(results.len(), 1);
+        assert!(results[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("SAFETY"));
+    }
+}
@@ -0,0 +1,289 @@
+//! HTTP client settings shared by every provider: proxy, extra root certificates,
+//! client certificates and timeouts
+//!
+//! Without these settings reqwest's defaults apply, which already honour the usual
+//! HTTPS_PROXY and NO_PROXY variables.
+
+use futures::stream::{self, StreamExt};
+use reqwest::{Certificate, Client, Identity, NoProxy, Proxy, RequestBuilder, Response};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{ChatStream, LLMError};
+
+/// HTTP client settings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpConfig {
+    pub proxy: Option<String>, // Proxy URL for all requests, e.g. http://proxy.corp:3128
+    pub no_proxy: Option<String>, // Hosts reached without the proxy, separated by commas
+    pub ca_bundle: Option<PathBuf>, // PEM file of root certificates trusted in addition to the system ones
+    pub client_cert: Option<PathBuf>, // PEM certificate chain for mutual TLS
+    pub client_key: Option<PathBuf>, // PEM private key (PKCS#8) of the client certificate
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>, // Longest wait for the response, then between two pieces of it
+}
+
+impl HttpConfig {
+    /// Build a client with these settings
+    pub fn client(&self) -> Result<Client, LLMError> {
+        let mut builder = Client::builder();
+
+        if let Some(url) = &self.proxy {
+            let proxy = Proxy::all(url)
+                .map_err(|e| LLMError::ConfigError(format!("Invalid proxy {}: {}", url, e)))?
+                .no_proxy(self.no_proxy.as_deref().and_then(NoProxy::from_string));
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(path) = &self.ca_bundle {
+            let certificates = Certificate::from_pem_bundle(&read(path)?).map_err(|e| {
+                LLMError::ConfigError(format!("Invalid certificate in {}: {}", path.display(), e))
+            })?;
+            if certificates.is_empty() {
+                return Err(LLMError::ConfigError(format!(
+                    "No certificate found in {}",
+                    path.display()
+                )));
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity =
+                    Identity::from_pkcs8_pem(&read(cert)?, &read(key)?).map_err(|e| {
+                        LLMError::ConfigError(format!(
+                            "Invalid client certificate {} or key {}: {}",
+                            cert.display(),
+                            key.display(),
+                            e
+                        ))
+                    })?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(LLMError::ConfigError(
+                    "A client certificate needs both the certificate and its key".to_string(),
+                ))
+            }
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        builder
+            .build()
+            .map_err(|e| LLMError::ConfigError(e.to_string()))
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, LLMError> {
+    fs::read(path)
+        .map_err(|e| LLMError::ConfigError(format!("Cannot read {}: {}", path.display(), e)))
+}
+
+/// Send a request, giving up if the response does not start within the read timeout
+pub async fn send(
+    request: RequestBuilder,
+    read_timeout: Option<Duration>,
+) -> Result<Response, LLMError> {
+    let response = match read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, request.send())
+            .await
+            .map_err(|_| {
+                LLMError::Timeout(format!("No response within {:.1}s", timeout.as_secs_f64()))
+            })?,
+        None => request.send().await,
+    };
+    response.map_err(LLMError::from_reqwest)
+}
+
+/// Read the whole body of a response, giving up if it does not end within the read timeout
+pub async fn read_text(
+    response: Response,
+    read_timeout: Option<Duration>,
+) -> Result<String, LLMError> {
+    let text = match read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, response.text())
+            .await
+            .map_err(|_| {
+                LLMError::Timeout(format!(
+                    "Response not complete within {:.1}s",
+                    timeout.as_secs_f64()
+                ))
+            })?,
+        None => response.text().await,
+    };
+    text.map_err(LLMError::from_reqwest)
+}
+
+/// End a stream with a timeout error when nothing arrives within the read timeout
+pub fn with_read_timeout(stream: ChatStream, read_timeout: Option<Duration>) -> ChatStream {
+    let Some(timeout) = read_timeout else {
+        return stream;
+    };
+    Box::pin(stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(event)) => Some((event, Some(stream))),
+            Ok(None) => None,
+            Err(_) => {
+                let error = LLMError::Timeout(format!(
+                    "No data received for {:.1}s",
+                    timeout.as_secs_f64()
+                ));
+                Some((Err(error), None))
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::openai::OpenAIProvider;
+    use crate::llm::stub_server::{self, StubResponse};
+    use crate::llm::{LLMConfig, LLMProvider, Message, StreamEvent};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_invalid_settings_are_reported() {
+        let config = HttpConfig {
+            proxy: Some("not a url".to_string()),
+            ..HttpConfig::default()
+        };
+        let error = config.client().unwrap_err().to_string();
+        assert!(error.contains("Invalid proxy not a url"), "{}", error);
+
+        let config = HttpConfig {
+            ca_bundle: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..HttpConfig::default()
+        };
+        let error = config.client().unwrap_err().to_string();
+        assert!(
+            error.contains("Cannot read /nonexistent/ca.pem"),
+            "{}",
+            error
+        );
+
+        let config = HttpConfig {
+            client_cert: Some(PathBuf::from("/nonexistent/client.pem")),
+            ..HttpConfig::default()
+        };
+        assert!(matches!(config.client(), Err(LLMError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_bundle_without_certificates_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("empty.pem");
+        fs::write(&path, "not a certificate\n").unwrap();
+        let config = HttpConfig {
+            ca_bundle: Some(path.clone()),
+            ..HttpConfig::default()
+        };
+        let error = config.client().unwrap_err().to_string();
+        assert!(error.contains("No certificate found"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_the_proxy() {
+        let body = r#"{"choices":[{"message":{"content":"hi"},"finish_reason":"stop"}]}"#;
+        let (proxy_url, server) =
+            stub_server::serve(vec![StubResponse::new(200, "application/json", body)]).await;
+        let provider = OpenAIProvider::new(LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            api_key: "key".to_string(),
+            base_url: Some("http://api.example.invalid/v1".to_string()),
+            http: HttpConfig {
+                proxy: Some(proxy_url),
+                ..HttpConfig::default()
+            },
+            ..LLMConfig::default()
+        })
+        .unwrap();
+
+        let completion = provider.complete(vec![Message::user("hi")]).await.unwrap();
+        assert_eq!(completion.text, "hi");
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST http://api.example.invalid/v1/chat/completions HTTP/1.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // Accept the connection and never answer
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(socket);
+        });
+
+        let request = Client::new().get(url);
+        let result = send(request, Some(Duration::from_millis(50))).await;
+        assert!(matches!(result, Err(LLMError::Timeout(_))));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // Send the headers and part of the body, then nothing more
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 100\r\n\r\n{\"choices\"")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(socket);
+        });
+
+        let provider = OpenAIProvider::new(LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            api_key: "key".to_string(),
+            base_url: Some(url),
+            http: HttpConfig {
+                read_timeout: Some(Duration::from_millis(200)),
+                ..HttpConfig::default()
+            },
+            ..LLMConfig::default()
+        })
+        .unwrap();
+
+        let result = provider.complete(vec![Message::user("hi")]).await;
+        assert!(matches!(result, Err(LLMError::Timeout(_))), "{:?}", result);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream_times_out() {
+        let text = StreamEvent::TextDelta("hello".to_string());
+        let stalled: ChatStream =
+            Box::pin(stream::iter(vec![Ok(text.clone())]).chain(stream::pending()));
+        let mut stream = with_read_timeout(stalled, Some(Duration::from_millis(50)));
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), text);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(LLMError::Timeout(_)))
+        ));
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,130 @@
+//! Images attached to a question, e.g. a screenshot of an error dialog
+//!
+//! Images are checked before anything is sent, so that a wrong file fails fast instead of
+//! being rejected by the provider after the upload.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fs;
+use std::path::Path;
+
+use super::LLMError;
+
+/// Largest image accepted once encoded in base64, the limit of Anthropic (OpenAI accepts
+/// up to 20 MB). Files of up to 3.75 MB fit.
+pub const MAX_IMAGE_BYTES: u64 = 5 * 1024 * 1024;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const JPEG_SIGNATURE: &[u8] = b"\xff\xd8\xff";
+
+/// Image in a format every vision model takes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub media_type: &'static str, // image/png or image/jpeg
+    pub data: String,             // Base64 of the file
+}
+
+impl Image {
+    /// Read an image file, checking its size and format
+    pub fn load(path: &Path) -> Result<Self, LLMError> {
+        let cannot_read = |e: std::io::Error| {
+            LLMError::ConfigError(format!("Cannot read {}: {}", path.display(), e))
+        };
+        let size = fs::metadata(path).map_err(cannot_read)?.len();
+        // Base64 takes 4 characters for every 3 bytes
+        let encoded_size = 4 * size.div_ceil(3);
+        if encoded_size > MAX_IMAGE_BYTES {
+            return Err(LLMError::ConfigError(format!(
+                "{} is {:.1} MB once encoded, more than the limit of {} MB",
+                path.display(),
+                encoded_size as f64 / (1024.0 * 1024.0),
+                MAX_IMAGE_BYTES / (1024 * 1024)
+            )));
+        }
+        let bytes = fs::read(path).map_err(cannot_read)?;
+        Self::from_bytes(&bytes).ok_or_else(|| {
+            LLMError::ConfigError(format!("{} is not a PNG or JPEG image", path.display()))
+        })
+    }
+
+    /// Image of the given file contents, or None if they are not PNG or JPEG.
+    /// The format is told by the contents, as file names may lie.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let media_type = if bytes.starts_with(PNG_SIGNATURE) {
+            "image/png"
+        } else if bytes.starts_with(JPEG_SIGNATURE) {
+            "image/jpeg"
+        } else {
+            return None;
+        };
+        Some(Self {
+            media_type,
+            data: STANDARD.encode(bytes),
+        })
+    }
+
+    /// The image as a `data:` URL
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_is_told_by_contents() {
+        let png = Image::from_bytes(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        assert_eq!(png.media_type, "image/png");
+        assert_eq!(png.data, "iVBORw0KGgoAAAANSUhEUg==");
+        assert_eq!(
+            png.data_url(),
+            "data:image/png;base64,iVBORw0KGgoAAAANSUhEUg=="
+        );
+
+        let jpeg = Image::from_bytes(b"\xff\xd8\xff\xe0\0\x10JFIF").unwrap();
+        assert_eq!(jpeg.media_type, "image/jpeg");
+
+        assert_eq!(Image::from_bytes(b"GIF89a"), None);
+        assert_eq!(Image::from_bytes(b""), None);
+    }
+
+    #[test]
+    fn test_load_checks_size_and_format() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("shot.png");
+
+        fs::write(&path, "not an image").unwrap();
+        let error = Image::load(&path).unwrap_err().to_string();
+        assert!(error.contains("is not a PNG or JPEG image"), "{}", error);
+
+        // The largest file whose base64 fits the limit
+        let mut largest = PNG_SIGNATURE.to_vec();
+        largest.resize(MAX_IMAGE_BYTES as usize / 4 * 3, 0);
+        fs::write(&path, &largest).unwrap();
+        assert_eq!(
+            Image::load(&path).unwrap().data.len() as u64,
+            MAX_IMAGE_BYTES
+        );
+
+        largest.push(0);
+        fs::write(&path, largest).unwrap();
+        let error = Image::load(&path).unwrap_err().to_string();
+        assert!(
+            error.contains("5.0 MB once encoded, more than the limit of 5 MB"),
+            "{}",
+            error
+        );
+
+        let error = Image::load(Path::new("/nonexistent/shot.png"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("Cannot read /nonexistent/shot.png"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Provider replaying canned responses from fixture files, for offline use and testing
+//!
+//! A fixture file holds one JSON fixture or an array of them; a directory holds any number
+//! of `*.json` fixture files, read in the order of their names. The first fixture matching
+//! the rendered prompt (all messages joined by newlines) is replayed:
+//!
+//! ```json
+//! {
+//!   "regex": "list .*files",
+//!   "response": "Run `ls -la`",
+//!   "chunk_size": 4,
+//!   "delay_ms": 20,
+//!   "error": { "after_chunks": 2, "class": "stream_interrupted", "message": "reset" }
+//! }
+//! ```
+//!
+//! Instead of `regex`, `hash` matches the SHA-256 of the rendered prompt, which is shown in
+//! the debug output. A fixture with neither matches every prompt.
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path, time::Duration};
+
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::{ChatStream, LLMConfig, LLMError, LLMProvider, Message, StopReason, StreamEvent};
+
+const DEFAULT_CHUNK_SIZE: usize = 16; // Characters per text delta
+
+const ENV_MOCK_FIXTURES: &str = "ASK_SH_MOCK_FIXTURES";
+const ENV_MOCK_MODEL: &str = "ASK_SH_MOCK_MODEL";
+
+#[derive(Debug)]
+pub struct MockProvider {
+    model: String,
+    fixtures: Vec<Fixture>,
+    debug: bool, // Report the prompt hash to stderr
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    #[serde(default, with = "serde_regex")]
+    regex: Option<Regex>,
+    hash: Option<String>, // Lowercase hex SHA-256 of the rendered prompt
+    #[serde(default)]
+    response: String,
+    chunk_size: Option<usize>,
+    #[serde(default)]
+    delay_ms: u64, // Delay before every chunk
+    stop_reason: Option<String>, // e.g. "max_tokens", default "end_turn"
+    error: Option<InjectedError>,
+}
+
+/// Error injected in place of the rest of the response
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct InjectedError {
+    #[serde(default)]
+    after_chunks: usize, // 0 fails before any text
+    class: String, // See `LLMError::class`
+    #[serde(default)]
+    message: String,
+}
+
+/// Deserialize regular expressions from strings
+mod serde_regex {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|pattern| Regex::new(&pattern).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FixtureFile {
+    One(Fixture),
+    Many(Vec<Fixture>),
+}
+
+impl InjectedError {
+    fn to_error(&self) -> Result<LLMError, LLMError> {
+        LLMError::from_class(&self.class, self.message.clone()).ok_or_else(|| {
+            LLMError::ConfigError(format!(
+                "Unknown error class in mock fixture: {}",
+                self.class
+            ))
+        })
+    }
+}
+
+impl Fixture {
+    fn matches(&self, prompt: &str, hash: &str) -> bool {
+        let regex_matches = self.regex.as_ref().is_none_or(|re| re.is_match(prompt));
+        let hash_matches = self
+            .hash
+            .as_ref()
+            .is_none_or(|h| h.eq_ignore_ascii_case(hash));
+        regex_matches && hash_matches
+    }
+
+    /// Events to replay, each preceded by the delay
+    fn events(&self, model: &str) -> Vec<Result<StreamEvent, LLMError>> {
+        let chars: Vec<char> = self.response.chars().collect();
+        let chunk_size = self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+
+        let mut events = vec![Ok(StreamEvent::Metadata {
+            id: None,
+            model: Some(model.to_string()),
+        })];
+        for (i, chunk) in chars.chunks(chunk_size).enumerate() {
+            if let Some(error) = self.error.as_ref().filter(|e| e.after_chunks == i) {
+                events.push(Err(error.to_error().unwrap_or_else(|e| e)));
+                return events;
+            }
+            events.push(Ok(StreamEvent::TextDelta(chunk.iter().collect())));
+        }
+        // An error after the last chunk still interrupts the stream before it stops
+        if let Some(error) = &self.error {
+            events.push(Err(error.to_error().unwrap_or_else(|e| e)));
+            return events;
+        }
+        let reason = self.stop_reason.as_deref().unwrap_or("end_turn");
+        events.push(Ok(StreamEvent::Stop(StopReason::from_provider(reason))));
+        events
+    }
+}
+
+impl MockProvider {
+    pub fn new(config: LLMConfig) -> Result<Self, LLMError> {
+        let path = config.base_url.ok_or_else(|| {
+            LLMError::ConfigError("Mock fixture file or directory not set".to_string())
+        })?;
+        let fixtures = load_fixtures(Path::new(&path))?;
+
+        // Validate injected errors now rather than in the middle of a test run
+        for error in fixtures.iter().filter_map(|f| f.error.as_ref()) {
+            error.to_error()?;
+        }
+
+        Ok(Self {
+            model: config.model,
+            fixtures,
+            debug: config.debug,
+        })
+    }
+}
+
+fn load_fixtures(path: &Path) -> Result<Vec<Fixture>, LLMError> {
+    let read_error =
+        |e: std::io::Error| LLMError::ConfigError(format!("Cannot read {}: {}", path.display(), e));
+
+    if path.is_dir() {
+        let mut files: Vec<_> = fs::read_dir(path)
+            .map_err(read_error)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|file| file.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        files.sort();
+
+        let mut fixtures = Vec::new();
+        for file in files {
+            fixtures.extend(load_fixtures(&file)?);
+        }
+        return Ok(fixtures);
+    }
+
+    let content = fs::read_to_string(path).map_err(read_error)?;
+    match serde_json::from_str::<FixtureFile>(&content) {
+        Ok(FixtureFile::One(fixture)) => Ok(vec![fixture]),
+        Ok(FixtureFile::Many(fixtures)) => Ok(fixtures),
+        // The untagged enum hides the reason, so parse again for a useful message
+        Err(_) => Err(LLMError::ConfigError(format!(
+            "Invalid mock fixture {}: {}",
+            path.display(),
+            serde_json::from_str::<Fixture>(&content)
+                .err()
+                .map_or("unexpected format".to_string(), |e| e.to_string())
+        ))),
+    }
+}
+
+/// SHA-256 of the prompt as lowercase hex
+fn prompt_hash(prompt: &str) -> String {
+    Sha256::digest(prompt.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[async_trait]
+impl LLMProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let prompt = messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let hash = prompt_hash(&prompt);
+        if self.debug {
+            eprintln!("mock_prompt_hash: {}", hash);
+        }
+
+        let fixture = self
+            .fixtures
+            .iter()
+            .find(|fixture| fixture.matches(&prompt, &hash))
+            .ok_or_else(|| {
+                LLMError::ApiError(format!("No mock fixture matches the prompt {}", hash))
+            })?;
+
+        let delay = Duration::from_millis(fixture.delay_ms);
+        Ok(Box::pin(stream::iter(fixture.events(&self.model)).then(
+            move |event| async move {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                event
+            },
+        )))
+    }
+}
+
+pub struct MockFactory;
+
+impl ProviderFactory for MockFactory {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::model(ENV_MOCK_MODEL).with_default("mock"),
+            // Fixture file or directory replayed instead of calling a model
+            Setting::endpoint(ENV_MOCK_FIXTURES),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        Ok(LLMConfig {
+            model: settings.require(ENV_MOCK_MODEL)?,
+            base_url: Some(settings.require(ENV_MOCK_FIXTURES)?),
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(MockProvider::new(config)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Write fixture files into a fresh temporary directory, removed when dropped
+    fn fixture_dir(files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for (file, content) in files {
+            fs::write(dir.path().join(file), content).unwrap();
+        }
+        dir
+    }
+
+    fn mock_provider(path: &Path) -> Result<MockProvider, LLMError> {
+        MockProvider::new(LLMConfig {
+            provider: "mock".to_string(),
+            model: "mock".to_string(),
+            base_url: Some(path.display().to_string()),
+            ..Default::default()
+        })
+    }
+
+    async fn collect(
+        provider: &MockProvider,
+        prompt: &str,
+    ) -> Result<Vec<Result<StreamEvent, LLMError>>, LLMError> {
+        let stream = provider.chat_stream(vec![Message::user(prompt)]).await?;
+        Ok(stream.collect().await)
+    }
+
+    #[tokio::test]
+    async fn test_matches_by_regex_hash_and_default() {
+        let hash = prompt_hash("exact prompt");
+        let dir = fixture_dir(&[
+            (
+                "01-regex.json",
+                r#"{"regex": "list .*files", "response": "Run `ls`", "chunk_size": 3}"#,
+            ),
+            (
+                "02-hash.json",
+                &format!(r#"[{{"hash": "{}", "response": "hashed"}}]"#, hash),
+            ),
+            (
+                "03-default.json",
+                r#"{"response": "default", "stop_reason": "max_tokens"}"#,
+            ),
+            ("notes.txt", "not a fixture"),
+        ]);
+        let provider = mock_provider(dir.path()).unwrap();
+
+        let events = collect(&provider, "please list all files").await.unwrap();
+        let texts: Vec<StreamEvent> = events.into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(
+            texts[1..],
+            [
+                StreamEvent::TextDelta("Run".to_string()),
+                StreamEvent::TextDelta(" `l".to_string()),
+                StreamEvent::TextDelta("s`".to_string()),
+                StreamEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+
+        let events = collect(&provider, "exact prompt").await.unwrap();
+        assert_eq!(
+            events[1].as_ref().unwrap(),
+            &StreamEvent::TextDelta("hashed".to_string())
+        );
+
+        let events = collect(&provider, "anything else").await.unwrap();
+        assert_eq!(
+            events.last().unwrap().as_ref().unwrap(),
+            &StreamEvent::Stop(StopReason::MaxTokens)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_injects_errors() {
+        let dir = fixture_dir(&[(
+            "fixtures.json",
+            r#"[
+                    {"regex": "^before", "error": {"class": "overloaded", "message": "busy"}},
+                    {"response": "abcdef", "chunk_size": 2, "delay_ms": 1,
+                     "error": {"after_chunks": 2, "class": "stream_interrupted", "message": "reset"}}
+                ]"#,
+        )]);
+        let provider = mock_provider(dir.path()).unwrap();
+
+        let events = collect(&provider, "before any text").await.unwrap();
+        assert!(matches!(events[1], Err(LLMError::Overloaded { .. })));
+
+        let events = collect(&provider, "mid-stream").await.unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[2].as_ref().unwrap(),
+            &StreamEvent::TextDelta("cd".to_string())
+        );
+        assert!(matches!(events[3], Err(LLMError::StreamInterrupted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_fixtures_and_no_match() {
+        let dir = fixture_dir(&[(
+            "bad.json",
+            r#"{"response": "x", "error": {"class": "nope"}}"#,
+        )]);
+        assert!(matches!(
+            mock_provider(dir.path()),
+            Err(LLMError::ConfigError(_))
+        ));
+
+        let dir = fixture_dir(&[("only.json", r#"{"regex": "^ls$"}"#)]);
+        let provider = mock_provider(dir.path()).unwrap();
+        let Err(err) = provider.chat_stream(vec![Message::user("pwd")]).await else {
+            panic!("expected an error");
+        };
+        assert!(err.to_string().contains(&prompt_hash("pwd")));
+    }
+}
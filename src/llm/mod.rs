@@ -1,8 +1,11 @@
 use async_trait::async_trait;
-use futures::Stream;
-use std::{fmt::Debug, pin::Pin};
+use futures::{Stream, StreamExt};
+use std::{collections::BTreeMap, fmt::Debug, pin::Pin, time::Duration};
 use thiserror::Error;
 
+use http::HttpConfig;
+use image::Image;
+
 /// Error from LLM provider
 #[derive(Debug, Error)]
 pub enum LLMError {
@@ -15,32 +18,584 @@ pub enum LLMError {
     #[error("Network error: {0}")]
     NetworkError(String),
 
-    #[error("Invalid request: {0}")]
-    InvalidRequestError(String),
+    #[error("{message} (HTTP {status})")]
+    HttpStatusError {
+        status: u16,
+        message: String,
+        retry_after: Option<Duration>, // From Retry-After or retry-after-ms headers
+    },
+
+    #[error("Authentication failed: {0}")]
+    Authentication(String),
+
+    #[error("Rate limit exceeded: {message}")]
+    RateLimit {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Quota exhausted: {0}")]
+    QuotaExhausted(String),
+
+    #[error("Model not found: {0}")]
+    ModelNotFound(String),
+
+    #[error("Context too long: {0}")]
+    ContextTooLong(String),
+
+    #[error("Provider overloaded: {message}")]
+    Overloaded {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+
+    #[error("Stream interrupted: {0}")]
+    StreamInterrupted(String),
+}
+
+impl LLMError {
+    /// Classify an error reported by a provider.
+    /// `kind` is the error type or code from the response body, e.g. `overloaded_error`.
+    pub fn from_api_error(
+        status: Option<u16>,
+        kind: Option<&str>,
+        message: String,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let kind = kind.unwrap_or_default();
+        let lowercase = message.to_lowercase();
+        let mentions = |patterns: &[&str]| patterns.iter().any(|p| lowercase.contains(p));
+
+        if matches!(
+            kind,
+            "authentication_error" | "permission_error" | "invalid_api_key"
+        ) || matches!(status, Some(401 | 403))
+        {
+            LLMError::Authentication(message)
+        } else if kind == "insufficient_quota"
+            || mentions(&["credit balance", "exceeded your current quota"])
+        {
+            LLMError::QuotaExhausted(message)
+        } else if matches!(kind, "context_length_exceeded" | "request_too_large")
+            || status == Some(413)
+            || mentions(&["prompt is too long", "context length", "context window"])
+        {
+            LLMError::ContextTooLong(message)
+        } else if matches!(kind, "model_not_found" | "not_found_error") || status == Some(404) {
+            LLMError::ModelNotFound(message)
+        } else if matches!(kind, "rate_limit_error" | "rate_limit_exceeded") || status == Some(429)
+        {
+            LLMError::RateLimit {
+                message,
+                retry_after,
+            }
+        } else if kind == "overloaded_error" || matches!(status, Some(503 | 529)) {
+            LLMError::Overloaded {
+                message,
+                retry_after,
+            }
+        } else if kind == "timeout" || matches!(status, Some(408 | 504)) {
+            LLMError::Timeout(message)
+        } else if let Some(status) = status {
+            LLMError::HttpStatusError {
+                status,
+                message,
+                retry_after,
+            }
+        } else {
+            LLMError::ApiError(message)
+        }
+    }
+
+    /// Classify a failure of the HTTP client
+    pub fn from_reqwest(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            LLMError::Timeout(error.to_string())
+        } else {
+            LLMError::NetworkError(error.to_string())
+        }
+    }
+
+    /// Whether the request may succeed if sent again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LLMError::NetworkError(_)
+            | LLMError::RateLimit { .. }
+            | LLMError::Overloaded { .. }
+            | LLMError::Timeout(_)
+            | LLMError::StreamInterrupted(_) => true,
+            LLMError::HttpStatusError { status, .. } => matches!(status, 409 | 500..=599),
+            _ => false,
+        }
+    }
+
+    /// Delay requested by the server before retrying
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LLMError::HttpStatusError { retry_after, .. }
+            | LLMError::RateLimit { retry_after, .. }
+            | LLMError::Overloaded { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Process exit code, distinct for each kind of failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LLMError::ApiError(_) | LLMError::HttpStatusError { .. } => 1,
+            LLMError::ConfigError(_) => 2,
+            LLMError::Authentication(_) => 3,
+            LLMError::RateLimit { .. } => 4,
+            LLMError::QuotaExhausted(_) => 5,
+            LLMError::ModelNotFound(_) => 6,
+            LLMError::ContextTooLong(_) => 7,
+            LLMError::Overloaded { .. } => 8,
+            LLMError::Timeout(_) => 9,
+            LLMError::NetworkError(_) => 10,
+            LLMError::StreamInterrupted(_) => 11,
+        }
+    }
+
+    /// Build an error from the short name of its class, see `class`
+    pub fn from_class(class: &str, message: String) -> Option<Self> {
+        Some(match class {
+            "api" => LLMError::ApiError(message),
+            "config" => LLMError::ConfigError(message),
+            "network" => LLMError::NetworkError(message),
+            "server" => LLMError::HttpStatusError {
+                status: 500,
+                message,
+                retry_after: None,
+            },
+            "http" => LLMError::HttpStatusError {
+                status: 400,
+                message,
+                retry_after: None,
+            },
+            "auth" => LLMError::Authentication(message),
+            "rate_limit" => LLMError::RateLimit {
+                message,
+                retry_after: None,
+            },
+            "quota" => LLMError::QuotaExhausted(message),
+            "model_not_found" => LLMError::ModelNotFound(message),
+            "context_too_long" => LLMError::ContextTooLong(message),
+            "overloaded" => LLMError::Overloaded {
+                message,
+                retry_after: None,
+            },
+            "timeout" => LLMError::Timeout(message),
+            "stream_interrupted" => LLMError::StreamInterrupted(message),
+            _ => return None,
+        })
+    }
+
+    /// Short name of the kind of failure, used to configure the fallback chain
+    pub fn class(&self) -> &'static str {
+        match self {
+            LLMError::ApiError(_) => "api",
+            LLMError::ConfigError(_) => "config",
+            LLMError::NetworkError(_) => "network",
+            LLMError::HttpStatusError { status, .. } if *status >= 500 => "server",
+            LLMError::HttpStatusError { .. } => "http",
+            LLMError::Authentication(_) => "auth",
+            LLMError::RateLimit { .. } => "rate_limit",
+            LLMError::QuotaExhausted(_) => "quota",
+            LLMError::ModelNotFound(_) => "model_not_found",
+            LLMError::ContextTooLong(_) => "context_too_long",
+            LLMError::Overloaded { .. } => "overloaded",
+            LLMError::Timeout(_) => "timeout",
+            LLMError::StreamInterrupted(_) => "stream_interrupted",
+        }
+    }
+}
+
+/// Short names of all error classes, see `LLMError::class`
+pub const ERROR_CLASSES: &[&str] = &[
+    "api",
+    "config",
+    "network",
+    "server",
+    "http",
+    "auth",
+    "rate_limit",
+    "quota",
+    "model_not_found",
+    "context_too_long",
+    "overloaded",
+    "timeout",
+    "stream_interrupted",
+];
+
+/// Error classes that make the fallback chain try the next provider by default
+pub const DEFAULT_FALLBACK_ON: &[&str] = &[
+    "network",
+    "server",
+    "rate_limit",
+    "quota",
+    "overloaded",
+    "timeout",
+];
+
+/// Generation parameters. Unset parameters are left to the provider's defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerationParams {
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub stop: Vec<String>,                // Stop sequences
+    pub seed: Option<i64>,                // Not supported by Anthropic
+    pub reasoning_effort: Option<String>, // low, medium or high (for OpenAI and Azure reasoning models)
+    pub thinking_budget: Option<u32>, // Tokens the model may think before answering (for Anthropic)
 }
 
 /// LLM configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct LLMConfig {
     pub provider: String,
     pub model: String,
     pub api_key: String,
-    pub base_url: Option<String>, // Custom endpoint URL (for OpenAI)
+    pub base_url: Option<String>, // Custom endpoint URL, or fixture path (for mock)
+    pub extra_headers: Vec<(String, String)>, // Additional HTTP headers (for Anthropic)
+    pub num_ctx: Option<u32>,     // Context window size (for Ollama)
+    pub keep_alive: Option<String>, // How long the model stays loaded (for Ollama)
+    pub command: Option<String>,  // Command line run through `sh -c` (for exec)
+    pub api_version: Option<String>, // api-version query parameter (for Azure OpenAI)
+    pub generation: GenerationParams,
+    pub tools: Vec<ToolSpec>, // Tools the model may call (for OpenAI, Azure and Anthropic)
+    pub http: HttpConfig,     // Proxy, certificates and timeouts (for the providers talking HTTP)
+    pub prompt_caching: bool, // Mark the stable parts of the prompt as cacheable (for Anthropic)
+    pub output_schema: Option<OutputSchema>, // Answer with JSON instead of free text (for OpenAI, Azure and Anthropic)
+    pub fallback: Vec<LLMConfig>,            // Providers tried in order (for fallback)
+    pub fallback_on: Vec<String>, // Error classes that move on to the next provider (for fallback)
+    pub debug: bool,              // Report provider decisions to stderr
 }
 
-impl Default for LLMConfig {
-    fn default() -> Self {
+/// Role of a message in a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool, // Result of a tool call
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+/// Message in a conversation, sent to the provider in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,    // Tools called by the assistant
+    pub tool_call_id: Option<String>, // Call answered by a tool message
+    pub reasoning: Option<Reasoning>, // Reasoning of the assistant before calling tools
+    pub images: Vec<Image>,           // Attached to a user message
+}
+
+/// Reasoning of the model before its answer, shown apart from it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reasoning {
+    pub text: String,
+    pub signature: Option<String>, // Required by Anthropic to send the reasoning back
+}
+
+impl Message {
+    fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            reasoning: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    #[allow(dead_code)] // The CLI does not send follow-up turns yet
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+
+    /// Answer of the assistant that calls tools, with the text written before the calls
+    pub fn tool_calls(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::new(Role::Assistant, content)
+        }
+    }
+
+    /// Attach the reasoning that led to the tool calls, which some providers need back
+    pub fn with_reasoning(self, reasoning: Reasoning) -> Self {
+        let reasoning =
+            (!reasoning.text.is_empty() || reasoning.signature.is_some()).then_some(reasoning);
+        Self { reasoning, ..self }
+    }
+
+    /// Attach images to the message
+    pub fn with_images(self, images: Vec<Image>) -> Self {
+        Self { images, ..self }
+    }
+
+    /// Output of a tool, answering the call with the given ID
+    pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
         Self {
-            provider: String::new(),
-            model: String::new(),
-            api_key: String::new(),
-            base_url: None,
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+}
+
+/// Tool offered to the model
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value, // JSON Schema of the arguments
+}
+
+/// Model offered by a provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,                  // Name to use as the model setting
+    pub context_length: Option<u32>, // In tokens, if the provider tells
+}
+
+/// Schema of a JSON answer requested instead of free text.
+/// The JSON document then arrives as text deltas, so it can be shown while it streams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputSchema {
+    pub name: String,
+    pub schema: serde_json::Value, // JSON Schema of the answer
+}
+
+/// Call of a tool requested by the model
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String, // JSON object, possibly empty when the tool takes no arguments
+}
+
+impl ToolCall {
+    /// Parse the arguments, treating empty arguments as an empty object
+    pub fn parsed_arguments(&self) -> Result<serde_json::Value, serde_json::Error> {
+        if self.arguments.trim().is_empty() {
+            Ok(serde_json::json!({}))
+        } else {
+            serde_json::from_str(&self.arguments)
+        }
+    }
+}
+
+/// Token usage reported by the provider.
+/// Providers may report it in parts, e.g. input tokens first and output tokens at the end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    pub cache_read_tokens: Option<u32>, // Input tokens read from the prompt cache (Anthropic)
+    pub cache_write_tokens: Option<u32>, // Input tokens written to the prompt cache (Anthropic)
+}
+
+impl Usage {
+    /// Update with the counts present in a later report
+    pub fn merge(&mut self, other: Usage) {
+        self.input_tokens = other.input_tokens.or(self.input_tokens);
+        self.output_tokens = other.output_tokens.or(self.output_tokens);
+        self.cache_read_tokens = other.cache_read_tokens.or(self.cache_read_tokens);
+        self.cache_write_tokens = other.cache_write_tokens.or(self.cache_write_tokens);
+    }
+
+    /// Add the usage of another request, e.g. a later round of tool calls
+    pub fn add(&mut self, other: Usage) {
+        let sum = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        self.input_tokens = sum(self.input_tokens, other.input_tokens);
+        self.output_tokens = sum(self.output_tokens, other.output_tokens);
+        self.cache_read_tokens = sum(self.cache_read_tokens, other.cache_read_tokens);
+        self.cache_write_tokens = sum(self.cache_write_tokens, other.cache_write_tokens);
+    }
+}
+
+/// Why the model stopped generating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    EndTurn,
+    MaxTokens,
+    StopSequence,
+    ToolUse, // The model waits for the results of its tool calls
+    Other(String),
+}
+
+impl StopReason {
+    /// Map the finish/stop reason names used by the providers
+    pub fn from_provider(reason: &str) -> Self {
+        match reason {
+            "stop" | "end_turn" => StopReason::EndTurn,
+            "length" | "max_tokens" => StopReason::MaxTokens,
+            "stop_sequence" => StopReason::StopSequence,
+            "tool_calls" | "tool_use" => StopReason::ToolUse,
+            other => StopReason::Other(other.to_string()),
         }
     }
 }
 
+/// Event of a chat stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    TextDelta(String),
+    /// Piece of the reasoning before the answer. It is not part of the answer's text.
+    ReasoningDelta(String),
+    /// Signature of the reasoning so far, sent back with it in the next round of tool calls
+    ReasoningSignature(String),
+    /// Start of a tool call. `index` identifies the call in the following deltas.
+    ToolCallStart {
+        index: usize,
+        id: String,
+        name: String,
+    },
+    /// Piece of the JSON arguments of a tool call
+    ToolCallDelta {
+        index: usize,
+        arguments: String,
+    },
+    Usage(Usage),
+    Stop(StopReason),
+    Metadata {
+        id: Option<String>,    // Response ID assigned by the provider
+        model: Option<String>, // Model that actually answered
+    },
+}
+
 /// Type alias for chat stream
-pub type ChatStream = Pin<Box<dyn Stream<Item = Result<String, LLMError>> + Send + 'static>>;
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<StreamEvent, LLMError>> + Send + 'static>>;
+
+/// Whole answer of a non-streaming request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Completion {
+    pub text: String,
+    pub reasoning: Reasoning,
+    pub tool_calls: Vec<ToolCall>,
+    pub usage: Usage,
+    pub stop_reason: Option<StopReason>,
+    pub id: Option<String>,
+    pub model: Option<String>,
+}
+
+impl Completion {
+    /// Collect the events of a stream, failing on the first error
+    pub async fn from_stream(mut stream: ChatStream) -> Result<Self, LLMError> {
+        let mut builder = CompletionBuilder::default();
+        while let Some(event) = stream.next().await {
+            builder.push(event?);
+        }
+        Ok(builder.finish())
+    }
+
+    /// Stream of the answer in one piece, for code written against streams
+    pub fn into_stream(self) -> ChatStream {
+        let mut events = Vec::new();
+        if self.id.is_some() || self.model.is_some() {
+            events.push(StreamEvent::Metadata {
+                id: self.id,
+                model: self.model,
+            });
+        }
+        if !self.reasoning.text.is_empty() {
+            events.push(StreamEvent::ReasoningDelta(self.reasoning.text));
+        }
+        events.extend(
+            self.reasoning
+                .signature
+                .map(StreamEvent::ReasoningSignature),
+        );
+        if !self.text.is_empty() {
+            events.push(StreamEvent::TextDelta(self.text));
+        }
+        for (index, call) in self.tool_calls.into_iter().enumerate() {
+            events.push(StreamEvent::ToolCallStart {
+                index,
+                id: call.id,
+                name: call.name,
+            });
+            events.push(StreamEvent::ToolCallDelta {
+                index,
+                arguments: call.arguments,
+            });
+        }
+        events.extend(self.stop_reason.map(StreamEvent::Stop));
+        if self.usage != Usage::default() {
+            events.push(StreamEvent::Usage(self.usage));
+        }
+        Box::pin(futures::stream::iter(events.into_iter().map(Ok)))
+    }
+}
+
+/// Completion put together event by event, for code that also handles each event as it
+/// arrives, e.g. to print it
+#[derive(Debug, Default)]
+pub struct CompletionBuilder {
+    completion: Completion,
+    tool_calls: BTreeMap<usize, ToolCall>, // By index in the stream
+}
+
+impl CompletionBuilder {
+    pub fn push(&mut self, event: StreamEvent) {
+        let completion = &mut self.completion;
+        match event {
+            StreamEvent::TextDelta(text) => completion.text.push_str(&text),
+            StreamEvent::ReasoningDelta(text) => completion.reasoning.text.push_str(&text),
+            StreamEvent::ReasoningSignature(signature) => {
+                completion.reasoning.signature = Some(signature)
+            }
+            StreamEvent::ToolCallStart { index, id, name } => {
+                let call = self.tool_calls.entry(index).or_default();
+                call.id = id;
+                call.name = name;
+            }
+            StreamEvent::ToolCallDelta { index, arguments } => self
+                .tool_calls
+                .entry(index)
+                .or_default()
+                .arguments
+                .push_str(&arguments),
+            StreamEvent::Usage(usage) => completion.usage.merge(usage),
+            StreamEvent::Stop(reason) => completion.stop_reason = Some(reason),
+            StreamEvent::Metadata { id, model } => {
+                completion.id = id;
+                completion.model = model;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Completion {
+        self.completion.tool_calls = self.tool_calls.into_values().collect();
+        self.completion
+    }
+}
 
 /// Trait for LLM provider
 #[async_trait]
@@ -51,62 +606,70 @@ pub trait LLMProvider: Send + Sync + Debug {
     /// Returns the current model name
     fn model(&self) -> &str;
 
-    /// Get chat completion as a stream
-    async fn chat_stream(
-        &self,
-        system_message: String,
-        user_message: String,
-    ) -> Result<ChatStream, LLMError>;
+    /// Get chat completion of the conversation as a stream
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError>;
+
+    /// Get chat completion of the conversation in one piece. By default the stream is
+    /// collected; providers override it for endpoints that cannot stream.
+    async fn complete(&self, messages: Vec<Message>) -> Result<Completion, LLMError> {
+        Completion::from_stream(self.chat_stream(messages).await?).await
+    }
+
+    /// Whether images attached to messages are sent to the model
+    fn supports_images(&self) -> bool {
+        false
+    }
+
+    /// List the models available to the configured account, sorted by name
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        Err(LLMError::ConfigError(format!(
+            "{} cannot list its models",
+            self.name()
+        )))
+    }
 }
 
 pub mod anthropic;
+pub mod cache;
+pub mod exec;
+pub mod fallback;
+pub mod gemini;
+pub mod http;
+pub mod image;
+pub mod mock;
+pub mod ollama;
 pub mod openai;
+pub mod registry;
+pub mod retry;
+pub mod sse;
 
-/// Available LLM providers
-#[derive(Debug)]
-pub enum Provider {
-    OpenAI(openai::OpenAIProvider),
-    Anthropic(anthropic::AnthropicProvider),
-}
+#[cfg(test)]
+mod stub_server;
 
+/// Boxed providers, e.g. those built by the registry, are providers too
 #[async_trait]
-impl LLMProvider for Provider {
+impl<P: LLMProvider + ?Sized> LLMProvider for Box<P> {
     fn name(&self) -> &'static str {
-        match self {
-            Provider::OpenAI(p) => p.name(),
-            Provider::Anthropic(p) => p.name(),
-        }
+        (**self).name()
     }
 
     fn model(&self) -> &str {
-        match self {
-            Provider::OpenAI(p) => p.model(),
-            Provider::Anthropic(p) => p.model(),
-        }
+        (**self).model()
     }
 
-    async fn chat_stream(
-        &self,
-        system_message: String,
-        user_message: String,
-    ) -> Result<ChatStream, LLMError> {
-        match self {
-            Provider::OpenAI(p) => p.chat_stream(system_message, user_message).await,
-            Provider::Anthropic(p) => p.chat_stream(system_message, user_message).await,
-        }
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        (**self).chat_stream(messages).await
+    }
+
+    async fn complete(&self, messages: Vec<Message>) -> Result<Completion, LLMError> {
+        (**self).complete(messages).await
+    }
+
+    fn supports_images(&self) -> bool {
+        (**self).supports_images()
     }
-}
 
-/// Provider factory
-pub fn create_provider(config: LLMConfig) -> Result<Provider, LLMError> {
-    match config.provider.as_str() {
-        "openai" => Ok(Provider::OpenAI(openai::OpenAIProvider::new(config)?)),
-        "anthropic" => Ok(Provider::Anthropic(anthropic::AnthropicProvider::new(
-            config,
-        )?)),
-        _ => Err(LLMError::ConfigError(format!(
-            "Unknown provider: {}",
-            config.provider
-        ))),
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        (**self).list_models().await
     }
 }
@@ -0,0 +1,403 @@
+use async_trait::async_trait;
+use futures::stream::{self, Stream, StreamExt};
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::time::Duration;
+
+use super::http;
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::retry::retry_after;
+use super::{
+    ChatStream, GenerationParams, LLMConfig, LLMError, LLMProvider, Message, StopReason,
+    StreamEvent, Usage,
+};
+
+pub const OLLAMA_DEFAULT_HOST: &str = "http://localhost:11434";
+
+const ENV_OLLAMA_HOST: &str = "ASK_SH_OLLAMA_HOST";
+const ENV_OLLAMA_MODEL: &str = "ASK_SH_OLLAMA_MODEL";
+const ENV_OLLAMA_NUM_CTX: &str = "ASK_SH_OLLAMA_NUM_CTX";
+const ENV_OLLAMA_KEEP_ALIVE: &str = "ASK_SH_OLLAMA_KEEP_ALIVE";
+
+#[derive(Debug)]
+pub struct OllamaProvider {
+    client: Client,
+    read_timeout: Option<Duration>,
+    model: String,
+    host: String,
+    num_ctx: Option<u32>,
+    keep_alive: Option<String>,
+    generation: GenerationParams,
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: Options,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+}
+
+/// Model options, see the Modelfile documentation of Ollama
+#[derive(Serialize, Debug)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>, // Maximum number of tokens to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+}
+
+/// One line of the NDJSON response of /api/chat
+#[derive(Deserialize, Debug)]
+struct OllamaStreamChunk {
+    model: Option<String>,
+    message: Option<OllamaMessage>,
+    error: Option<String>,
+    #[serde(default)]
+    done: bool,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaErrorResponse {
+    error: String,
+}
+
+impl OllamaProvider {
+    pub fn new(config: LLMConfig) -> Result<Self, LLMError> {
+        let client = config.http.client()?;
+
+        let host = config
+            .base_url
+            .unwrap_or_else(|| OLLAMA_DEFAULT_HOST.to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        Ok(Self {
+            client,
+            read_timeout: config.http.read_timeout,
+            model: config.model,
+            host,
+            num_ctx: config.num_ctx,
+            keep_alive: config.keep_alive,
+            generation: config.generation,
+        })
+    }
+
+    fn create_request(&self, messages: &[Message]) -> OllamaRequest {
+        OllamaRequest {
+            model: self.model.clone(),
+            messages: messages
+                .iter()
+                .map(|message| OllamaMessage {
+                    role: message.role.as_str().to_string(),
+                    content: message.content.clone(),
+                })
+                .collect(),
+            stream: true,
+            options: Options {
+                num_ctx: self.num_ctx,
+                num_predict: self.generation.max_tokens,
+                temperature: self.generation.temperature,
+                top_p: self.generation.top_p,
+                stop: self.generation.stop.clone(),
+                seed: self.generation.seed,
+            },
+            keep_alive: self.keep_alive.clone(),
+        }
+    }
+
+    fn parse_ndjson_line(line: &[u8]) -> Vec<Result<StreamEvent, LLMError>> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return Vec::new();
+        }
+
+        let chunk = match serde_json::from_str::<OllamaStreamChunk>(line) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return vec![Err(LLMError::ApiError(format!(
+                    "Invalid response from Ollama: {}",
+                    e
+                )))]
+            }
+        };
+        if let Some(error) = chunk.error {
+            return vec![Err(LLMError::from_api_error(None, None, error, None))];
+        }
+
+        let mut events = Vec::new();
+        if let Some(message) = chunk.message.filter(|m| !m.content.is_empty()) {
+            events.push(Ok(StreamEvent::TextDelta(message.content)));
+        }
+        // The last line carries the statistics of the whole response
+        if chunk.done {
+            events.push(Ok(StreamEvent::Metadata {
+                id: None,
+                model: chunk.model,
+            }));
+            events.push(Ok(StreamEvent::Usage(Usage {
+                input_tokens: chunk.prompt_eval_count,
+                output_tokens: chunk.eval_count,
+                ..Default::default()
+            })));
+            if let Some(reason) = chunk.done_reason {
+                events.push(Ok(StreamEvent::Stop(StopReason::from_provider(&reason))));
+            }
+        }
+        events
+    }
+
+    /// Split a byte stream into NDJSON lines, keeping partial lines until they are complete
+    fn ndjson_stream<S, B>(bytes: S) -> impl Stream<Item = Result<StreamEvent, LLMError>>
+    where
+        S: Stream<Item = Result<B, reqwest::Error>> + Unpin,
+        B: AsRef<[u8]>,
+    {
+        stream::unfold(
+            (bytes, Vec::new(), false),
+            |(mut bytes, mut buffer, finished)| async move {
+                if finished {
+                    return None;
+                }
+                let mut items = Vec::new();
+                match bytes.next().await {
+                    Some(Ok(chunk)) => {
+                        buffer.extend_from_slice(chunk.as_ref());
+                        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                            let line: Vec<u8> = buffer.drain(..=pos).collect();
+                            items.extend(Self::parse_ndjson_line(&line));
+                        }
+                        Some((items, (bytes, buffer, false)))
+                    }
+                    Some(Err(e)) => {
+                        items.push(Err(LLMError::StreamInterrupted(e.to_string())));
+                        Some((items, (bytes, buffer, true)))
+                    }
+                    None => {
+                        // The last line may not be terminated by a newline
+                        items.extend(Self::parse_ndjson_line(&buffer));
+                        Some((items, (bytes, Vec::new(), true)))
+                    }
+                }
+            },
+        )
+        .flat_map(stream::iter)
+    }
+}
+
+#[async_trait]
+impl LLMProvider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let request = self.create_request(&messages);
+
+        let request_builder = self
+            .client
+            .post(format!("{}/api/chat", self.host))
+            .header(header::CONTENT_TYPE, "application/json")
+            .json(&request);
+        let response = http::send(request_builder, self.read_timeout)
+            .await
+            .map_err(|e| match e {
+                LLMError::NetworkError(message) => LLMError::NetworkError(format!(
+                    "{} (is Ollama running at {}?)",
+                    message, self.host
+                )),
+                other => other,
+            })?;
+
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let retry_after = retry_after(response.headers());
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            let message = serde_json::from_str::<OllamaErrorResponse>(&error_text)
+                .map(|e| e.error)
+                .unwrap_or(error_text);
+            return Err(LLMError::from_api_error(
+                Some(status),
+                None,
+                message,
+                retry_after,
+            ));
+        }
+
+        Ok(http::with_read_timeout(
+            Box::pin(Self::ndjson_stream(response.bytes_stream())),
+            self.read_timeout,
+        ))
+    }
+}
+
+pub struct OllamaFactory;
+
+impl ProviderFactory for OllamaFactory {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    // Ollama runs locally and needs no API key
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::model(ENV_OLLAMA_MODEL).with_default("llama3.2"),
+            Setting::endpoint(ENV_OLLAMA_HOST).with_default(OLLAMA_DEFAULT_HOST),
+            Setting::other(ENV_OLLAMA_NUM_CTX),
+            Setting::other(ENV_OLLAMA_KEEP_ALIVE),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        let num_ctx = match settings.get(ENV_OLLAMA_NUM_CTX) {
+            Some(value) => Some(value.parse::<u32>().map_err(|_| {
+                LLMError::ConfigError(format!(
+                    "{} must be a positive integer: {}",
+                    ENV_OLLAMA_NUM_CTX, value
+                ))
+            })?),
+            None => None,
+        };
+
+        Ok(LLMConfig {
+            model: settings.require(ENV_OLLAMA_MODEL)?,
+            base_url: Some(settings.require(ENV_OLLAMA_HOST)?),
+            num_ctx,
+            keep_alive: settings.get(ENV_OLLAMA_KEEP_ALIVE),
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(OllamaProvider::new(config)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub_server::{self, StubResponse};
+
+    fn test_config(base_url: Option<String>) -> LLMConfig {
+        LLMConfig {
+            provider: "ollama".to_string(),
+            model: "llama3".to_string(),
+            base_url,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ollama_provider_creation() {
+        let provider = OllamaProvider::new(test_config(None)).unwrap();
+        assert_eq!(provider.name(), "ollama");
+        assert_eq!(provider.model(), "llama3");
+        assert_eq!(provider.host, OLLAMA_DEFAULT_HOST);
+    }
+
+    #[tokio::test]
+    async fn test_ollama_chat_stream_against_stub() {
+        let response = StubResponse::new(200, "application/x-ndjson", "").with_chunks(vec![
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n{\"mess",
+            "age\":{\"role\":\"assistant\",\"content\":\"lo\"},\"done\":false}\n",
+            "{\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"length\",\"prompt_eval_count\":26,\"eval_count\":2}",
+        ]);
+        let (base_url, server) = stub_server::serve(vec![response]).await;
+
+        let mut config = test_config(Some(format!("{}/", base_url)));
+        config.num_ctx = Some(8192);
+        config.keep_alive = Some("10m".to_string());
+        config.generation.temperature = Some(0.0);
+        config.generation.seed = Some(7);
+        let provider = OllamaProvider::new(config).unwrap();
+
+        let stream = provider
+            .chat_stream(vec![Message::system("system"), Message::user("hi")])
+            .await
+            .unwrap();
+        let events: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::TextDelta("Hel".to_string()),
+                StreamEvent::TextDelta("lo".to_string()),
+                StreamEvent::Metadata {
+                    id: None,
+                    model: Some("llama3".to_string()),
+                },
+                StreamEvent::Usage(Usage {
+                    input_tokens: Some(26),
+                    output_tokens: Some(2),
+                    ..Default::default()
+                }),
+                StreamEvent::Stop(StopReason::MaxTokens),
+            ]
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0].request_line, "POST /api/chat HTTP/1.1");
+        let body = requests[0].json();
+        assert_eq!(body["model"], "llama3");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["options"]["num_ctx"], 8192);
+        assert_eq!(body["options"]["temperature"], 0.0);
+        assert_eq!(body["options"]["seed"], 7);
+        assert!(body["options"].get("num_predict").is_none());
+        assert_eq!(body["keep_alive"], "10m");
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "hi");
+    }
+
+    #[tokio::test]
+    async fn test_ollama_error_response() {
+        let response = StubResponse::new(
+            404,
+            "application/json",
+            "{\"error\":\"model \\\"llama3\\\" not found, try pulling it first\"}",
+        );
+        let (base_url, _server) = stub_server::serve(vec![response]).await;
+
+        let provider = OllamaProvider::new(test_config(Some(base_url))).unwrap();
+        let Err(err) = provider
+            .chat_stream(vec![Message::system("system"), Message::user("hi")])
+            .await
+        else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, LLMError::ModelNotFound(_)));
+        assert!(err.to_string().contains("not found, try pulling it first"));
+    }
+}
@@ -1,103 +1,819 @@
-use async_openai::{
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-        CreateChatCompletionRequestArgs,
-    },
-    Client,
-};
 use async_trait::async_trait;
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::time::Duration;
+
+use super::http;
+use super::registry::{ProviderFactory, Registry, Setting, Settings};
+use super::retry::retry_after;
+use super::sse::{sse_stream, SseEvent};
+use super::{
+    ChatStream, Completion, GenerationParams, LLMConfig, LLMError, LLMProvider, Message, ModelInfo,
+    OutputSchema, Reasoning, Role, StopReason, StreamEvent, ToolCall, ToolSpec, Usage,
+};
 
-use super::{ChatStream, LLMConfig, LLMError, LLMProvider};
+pub const OPENAI_DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+/// First API version taking `reasoning_effort` and `developer` messages
+pub const AZURE_DEFAULT_API_VERSION: &str = "2025-04-01-preview";
+
+/// Models that reason before answering, e.g. o3-mini. They take `developer` messages instead of
+/// `system` ones and `max_completion_tokens` instead of `max_tokens`, and reject sampling parameters.
+const REASONING_MODEL_PREFIXES: &[&str] = &["o1", "o3", "o4", "gpt-5"];
+/// Chat models sharing the name of a reasoning family, e.g. gpt-5-chat-latest
+const NON_REASONING_MODEL_PREFIXES: &[&str] = &["gpt-5-chat"];
+/// Early reasoning models, which take neither `system` nor `developer` messages
+const USER_ONLY_MODEL_PREFIXES: &[&str] = &["o1-mini", "o1-preview"];
+
+const ENV_OPENAI_API_KEY: &str = "ASK_SH_OPENAI_API_KEY";
+const ENV_OPENAI_MODEL: &str = "ASK_SH_OPENAI_MODEL";
+const ENV_OPENAI_BASE_URL: &str = "ASK_SH_OPENAI_BASE_URL";
+const ENV_AZURE_OPENAI_API_KEY: &str = "ASK_SH_AZURE_OPENAI_API_KEY";
+const ENV_AZURE_OPENAI_ENDPOINT: &str = "ASK_SH_AZURE_OPENAI_ENDPOINT";
+const ENV_AZURE_OPENAI_DEPLOYMENT: &str = "ASK_SH_AZURE_OPENAI_DEPLOYMENT";
+const ENV_AZURE_OPENAI_API_VERSION: &str = "ASK_SH_AZURE_OPENAI_API_VERSION";
 
 #[derive(Debug)]
 pub struct OpenAIProvider {
-    client: Client<OpenAIConfig>,
+    client: Client,
+    read_timeout: Option<Duration>,
+    model: String, // The deployment name on Azure
+    api_key: String,
+    base_url: String,
+    azure_api_version: Option<String>, // Set when talking to an Azure OpenAI resource
+    generation: GenerationParams,
+    tools: Vec<ToolSpec>,
+    output_schema: Option<OutputSchema>,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIRequest {
     model: String,
+    messages: Vec<OpenAIMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>, // Only allowed when streaming
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>, // Replaces max_tokens for reasoning models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<OpenAITool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+}
+
+#[derive(Serialize, Debug)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    format_type: &'static str,
+    json_schema: JsonSchema,
+}
+
+#[derive(Serialize, Debug)]
+struct JsonSchema {
+    name: String,
+    strict: bool, // The answer is guaranteed to match the schema
+    schema: serde_json::Value,
+}
+
+#[derive(Serialize, Debug)]
+struct StreamOptions {
+    include_usage: bool, // Adds a last chunk with the token usage
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIMessage {
+    role: String,
+    content: Option<OpenAIContent>, // Null when the assistant only calls tools
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAIToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+/// Plain text, or parts once images are attached
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum OpenAIContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Debug)]
+struct ImageUrl {
+    url: String, // A data: URL
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAITool {
+    #[serde(rename = "type")]
+    tool_type: &'static str,
+    function: FunctionDefinition,
+}
+
+#[derive(Serialize, Debug)]
+struct FunctionDefinition {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    tool_type: &'static str,
+    function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIStreamChunk {
+    id: Option<String>,
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<Choice>,
+    usage: Option<OpenAIUsage>,
+    error: Option<OpenAIError>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Choice {
+    delta: Option<ChoiceDelta>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIUsage {
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChoiceDelta {
+    content: Option<String>,
+    // Not returned by OpenAI, but by some compatible servers serving reasoning models
+    // (e.g. DeepSeek and vLLM use the first, OpenRouter the second)
+    reasoning_content: Option<String>,
+    reasoning: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+/// Body of a non-streaming response
+#[derive(Deserialize, Debug)]
+struct OpenAICompletion {
+    id: Option<String>,
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<CompletionChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    message: Option<CompletionMessage>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionMessage {
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    reasoning: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<CompletedToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletedToolCall {
+    id: String,
+    function: FunctionCall,
+}
+
+/// Piece of a tool call. The ID and name come with the first piece only.
+#[derive(Deserialize, Debug)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIError {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+    code: Option<serde_json::Value>, // A string, or a number on some compatible servers
+}
+
+impl OpenAIError {
+    /// The code is more specific than the type, e.g. `insufficient_quota` vs `requests`
+    fn kind(&self) -> Option<&str> {
+        self.code
+            .as_ref()
+            .and_then(|code| code.as_str())
+            .or(self.error_type.as_deref())
+    }
+}
+
+/// Body of a non-successful response
+#[derive(Deserialize, Debug)]
+struct OpenAIErrorResponse {
+    error: OpenAIError,
+}
+
+/// Body of the response of `/models`
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<OpenAIModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIModel {
+    id: String,
+    // Not returned by OpenAI, but by some compatible servers (e.g. vLLM or OpenRouter)
+    #[serde(alias = "context_window", alias = "max_model_len")]
+    context_length: Option<u32>,
 }
 
 impl OpenAIProvider {
     pub fn new(config: LLMConfig) -> Result<Self, LLMError> {
-        let mut openai_config = OpenAIConfig::new().with_api_key(config.api_key);
+        let client = config.http.client()?;
 
-        // Set custom base_url if specified
-        if let Some(base_url) = config.base_url {
-            openai_config = openai_config.with_api_base(&base_url);
-        }
+        // Azure has no default endpoint: every resource has its own
+        let azure_api_version = if config.provider == "azure" {
+            if config.base_url.is_none() {
+                return Err(LLMError::ConfigError(
+                    "Azure OpenAI endpoint not found".to_string(),
+                ));
+            }
+            Some(
+                config
+                    .api_version
+                    .unwrap_or_else(|| AZURE_DEFAULT_API_VERSION.to_string()),
+            )
+        } else {
+            None
+        };
 
-        let client = Client::with_config(openai_config);
+        // Set custom base_url if specified
+        let base_url = config
+            .base_url
+            .unwrap_or_else(|| OPENAI_DEFAULT_BASE_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
 
         Ok(Self {
             client,
+            read_timeout: config.http.read_timeout,
             model: config.model,
+            api_key: config.api_key,
+            base_url,
+            azure_api_version,
+            generation: config.generation,
+            tools: config.tools,
+            output_schema: config.output_schema,
         })
     }
+
+    /// Whether the model reasons before answering. Setting a reasoning effort marks
+    /// models whose name does not tell, e.g. Azure deployments.
+    fn is_reasoning_model(&self) -> bool {
+        self.generation.reasoning_effort.is_some()
+            || (REASONING_MODEL_PREFIXES
+                .iter()
+                .any(|prefix| self.model.starts_with(prefix))
+                && !NON_REASONING_MODEL_PREFIXES
+                    .iter()
+                    .any(|prefix| self.model.starts_with(prefix)))
+    }
+
+    /// Role of system messages for the model
+    fn system_role(&self) -> &'static str {
+        if USER_ONLY_MODEL_PREFIXES
+            .iter()
+            .any(|prefix| self.model.starts_with(prefix))
+        {
+            "user"
+        } else if self.is_reasoning_model() {
+            "developer"
+        } else {
+            "system"
+        }
+    }
+
+    fn create_request(&self, messages: &[Message]) -> OpenAIRequest {
+        let reasoning = self.is_reasoning_model();
+        OpenAIRequest {
+            model: self.model.clone(),
+            messages: messages
+                .iter()
+                .map(|message| OpenAIMessage {
+                    role: match message.role {
+                        Role::System => self.system_role().to_string(),
+                        role => role.as_str().to_string(),
+                    },
+                    content: if message.content.is_empty() && !message.tool_calls.is_empty() {
+                        None
+                    } else if message.images.is_empty() {
+                        Some(OpenAIContent::Text(message.content.clone()))
+                    } else {
+                        let text = ContentPart::Text {
+                            text: message.content.clone(),
+                        };
+                        let images = message.images.iter().map(|image| ContentPart::ImageUrl {
+                            image_url: ImageUrl {
+                                url: image.data_url(),
+                            },
+                        });
+                        Some(OpenAIContent::Parts(
+                            std::iter::once(text).chain(images).collect(),
+                        ))
+                    },
+                    tool_calls: message
+                        .tool_calls
+                        .iter()
+                        .map(|call| OpenAIToolCall {
+                            id: call.id.clone(),
+                            tool_type: "function",
+                            function: FunctionCall {
+                                name: call.name.clone(),
+                                // The arguments must be a JSON object, even when there are none
+                                arguments: call
+                                    .parsed_arguments()
+                                    .map_or(call.arguments.clone(), |a| a.to_string()),
+                            },
+                        })
+                        .collect(),
+                    tool_call_id: message.tool_call_id.clone(),
+                })
+                .collect(),
+            stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            max_tokens: self.generation.max_tokens.filter(|_| !reasoning),
+            max_completion_tokens: self.generation.max_tokens.filter(|_| reasoning),
+            reasoning_effort: self.generation.reasoning_effort.clone(),
+            temperature: self.generation.temperature.filter(|_| !reasoning),
+            top_p: self.generation.top_p.filter(|_| !reasoning),
+            stop: if reasoning {
+                Vec::new()
+            } else {
+                self.generation.stop.clone()
+            },
+            seed: self.generation.seed,
+            tools: self
+                .tools
+                .iter()
+                .map(|tool| OpenAITool {
+                    tool_type: "function",
+                    function: FunctionDefinition {
+                        name: tool.name.clone(),
+                        description: tool.description.clone(),
+                        parameters: tool.parameters.clone(),
+                    },
+                })
+                .collect(),
+            response_format: self.output_schema.as_ref().map(|output| ResponseFormat {
+                format_type: "json_schema",
+                json_schema: JsonSchema {
+                    name: output.name.clone(),
+                    strict: true,
+                    schema: output.schema.clone(),
+                },
+            }),
+        }
+    }
+
+    /// Parse one chunk. `metadata_sent` is set once the response metadata has been emitted,
+    /// as every chunk repeats it.
+    fn parse_event(
+        event: &SseEvent,
+        metadata_sent: &mut bool,
+    ) -> Vec<Result<StreamEvent, LLMError>> {
+        if event.data.trim() == "[DONE]" {
+            return Vec::new();
+        }
+
+        let chunk = match serde_json::from_str::<OpenAIStreamChunk>(&event.data) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return vec![Err(LLMError::ApiError(format!(
+                    "Invalid response from OpenAI: {}",
+                    e
+                )))]
+            }
+        };
+        if let Some(error) = chunk.error {
+            return vec![Err(LLMError::from_api_error(
+                None,
+                error.kind(),
+                error.message.clone(),
+                None,
+            ))];
+        }
+
+        let mut events = Vec::new();
+        if !*metadata_sent && (chunk.id.is_some() || chunk.model.is_some()) {
+            *metadata_sent = true;
+            events.push(Ok(StreamEvent::Metadata {
+                id: chunk.id,
+                model: chunk.model,
+            }));
+        }
+
+        let reasoning: String = chunk
+            .choices
+            .iter()
+            .filter_map(|choice| {
+                let delta = choice.delta.as_ref()?;
+                delta
+                    .reasoning_content
+                    .as_ref()
+                    .or(delta.reasoning.as_ref())
+            })
+            .map(String::as_str)
+            .collect();
+        if !reasoning.is_empty() {
+            events.push(Ok(StreamEvent::ReasoningDelta(reasoning)));
+        }
+
+        let content: String = chunk
+            .choices
+            .iter()
+            .filter_map(|choice| choice.delta.as_ref()?.content.as_deref())
+            .collect();
+        if !content.is_empty() {
+            events.push(Ok(StreamEvent::TextDelta(content)));
+        }
+
+        for call in chunk
+            .choices
+            .iter()
+            .filter_map(|choice| choice.delta.as_ref())
+            .flat_map(|delta| &delta.tool_calls)
+        {
+            let function = call.function.as_ref();
+            if let Some(id) = &call.id {
+                events.push(Ok(StreamEvent::ToolCallStart {
+                    index: call.index,
+                    id: id.clone(),
+                    name: function.and_then(|f| f.name.clone()).unwrap_or_default(),
+                }));
+            }
+            if let Some(arguments) = function
+                .and_then(|f| f.arguments.clone())
+                .filter(|a| !a.is_empty())
+            {
+                events.push(Ok(StreamEvent::ToolCallDelta {
+                    index: call.index,
+                    arguments,
+                }));
+            }
+        }
+
+        if let Some(reason) = chunk
+            .choices
+            .iter()
+            .find_map(|c| c.finish_reason.as_deref())
+        {
+            events.push(Ok(StreamEvent::Stop(StopReason::from_provider(reason))));
+        }
+        if let Some(usage) = chunk.usage {
+            events.push(Ok(StreamEvent::Usage(Usage {
+                input_tokens: usage.prompt_tokens,
+                output_tokens: usage.completion_tokens,
+                ..Default::default()
+            })));
+        }
+        events
+    }
+
+    /// Send a chat request, failing on a non-successful response
+    async fn send(&self, request: &OpenAIRequest) -> Result<reqwest::Response, LLMError> {
+        let mut request_builder = match &self.azure_api_version {
+            // Azure routes by deployment and authenticates with an api-key header
+            Some(api_version) => self
+                .client
+                .post(format!(
+                    "{}/openai/deployments/{}/chat/completions",
+                    self.base_url, self.model
+                ))
+                .query(&[("api-version", api_version)])
+                .header("api-key", &self.api_key),
+            None => self
+                .client
+                .post(format!("{}/chat/completions", self.base_url)),
+        }
+        .header(header::CONTENT_TYPE, "application/json");
+        // Local OpenAI-compatible servers often run without an API key
+        if self.azure_api_version.is_none() && !self.api_key.is_empty() {
+            request_builder = request_builder.bearer_auth(&self.api_key);
+        }
+
+        let response = http::send(request_builder.json(request), self.read_timeout).await?;
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+        Ok(response)
+    }
+
+    /// Read the body of a non-streaming response
+    async fn read_completion(
+        response: reqwest::Response,
+        read_timeout: Option<Duration>,
+    ) -> Result<Completion, LLMError> {
+        let body = http::read_text(response, read_timeout).await?;
+        let completion = serde_json::from_str::<OpenAICompletion>(&body)
+            .map_err(|e| LLMError::ApiError(format!("Invalid response from OpenAI: {}", e)))?;
+
+        let choice = completion.choices.into_iter().next();
+        let message = choice.as_ref().and_then(|c| c.message.as_ref());
+        Ok(Completion {
+            text: message.and_then(|m| m.content.clone()).unwrap_or_default(),
+            reasoning: Reasoning {
+                text: message
+                    .and_then(|m| m.reasoning_content.clone().or(m.reasoning.clone()))
+                    .unwrap_or_default(),
+                signature: None,
+            },
+            tool_calls: message
+                .map(|m| {
+                    m.tool_calls
+                        .iter()
+                        .map(|call| ToolCall {
+                            id: call.id.clone(),
+                            name: call.function.name.clone(),
+                            arguments: call.function.arguments.clone(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            usage: completion.usage.map_or(Usage::default(), |usage| Usage {
+                input_tokens: usage.prompt_tokens,
+                output_tokens: usage.completion_tokens,
+                ..Default::default()
+            }),
+            stop_reason: choice
+                .and_then(|c| c.finish_reason)
+                .map(|reason| StopReason::from_provider(&reason)),
+            id: completion.id,
+            model: completion.model,
+        })
+    }
+
+    /// Error described by a non-successful response
+    async fn error_from_response(response: reqwest::Response) -> LLMError {
+        let status = response.status().as_u16();
+        let retry_after = retry_after(response.headers());
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        match serde_json::from_str::<OpenAIErrorResponse>(&error_text) {
+            Ok(body) => LLMError::from_api_error(
+                Some(status),
+                body.error.kind(),
+                body.error.message.clone(),
+                retry_after,
+            ),
+            Err(_) => LLMError::from_api_error(Some(status), None, error_text, retry_after),
+        }
+    }
+
+    /// Convert the raw response body into a stream of events
+    fn event_stream<S, B>(bytes: S) -> impl Stream<Item = Result<StreamEvent, LLMError>>
+    where
+        S: Stream<Item = Result<B, reqwest::Error>> + Unpin,
+        B: AsRef<[u8]>,
+    {
+        sse_stream(bytes)
+            .scan(false, |metadata_sent, result| {
+                futures::future::ready(Some(match result {
+                    Ok(event) => Self::parse_event(&event, metadata_sent),
+                    Err(e) => vec![Err(LLMError::StreamInterrupted(e.to_string()))],
+                }))
+            })
+            .flat_map(futures::stream::iter)
+    }
 }
 
 #[async_trait]
 impl LLMProvider for OpenAIProvider {
     fn name(&self) -> &'static str {
-        "openai"
+        if self.azure_api_version.is_some() {
+            "azure"
+        } else {
+            "openai"
+        }
     }
 
     fn model(&self) -> &str {
         &self.model
     }
 
-    async fn chat_stream(
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let request = self.create_request(&messages);
+        let response = match self.send(&request).await {
+            // Some compatible servers reject fields they do not know. The answer then
+            // comes without its token usage rather than not at all.
+            Err(LLMError::HttpStatusError {
+                status: 400 | 422,
+                message,
+                ..
+            }) if message.contains("stream_options") => {
+                let request = OpenAIRequest {
+                    stream_options: None,
+                    ..request
+                };
+                self.send(&request).await?
+            }
+            result => result?,
+        };
+
+        // Some compatible servers ignore `stream` and answer in one piece
+        let is_json = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if is_json {
+            return Ok(Self::read_completion(response, self.read_timeout)
+                .await?
+                .into_stream());
+        }
+
+        Ok(http::with_read_timeout(
+            Box::pin(Self::event_stream(response.bytes_stream())),
+            self.read_timeout,
+        ))
+    }
+
+    async fn complete(&self, messages: Vec<Message>) -> Result<Completion, LLMError> {
+        let request = OpenAIRequest {
+            stream: false,
+            stream_options: None,
+            ..self.create_request(&messages)
+        };
+        Self::read_completion(self.send(&request).await?, self.read_timeout).await
+    }
+
+    fn supports_images(&self) -> bool {
+        true
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        // Azure lists the models it offers, not the deployments of the resource
+        if self.azure_api_version.is_some() {
+            return Err(LLMError::ConfigError(
+                "azure cannot list its deployments".to_string(),
+            ));
+        }
+
+        let mut request_builder = self.client.get(format!("{}/models", self.base_url));
+        if !self.api_key.is_empty() {
+            request_builder = request_builder.bearer_auth(&self.api_key);
+        }
+        let response = http::send(request_builder, self.read_timeout).await?;
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+
+        let body = http::read_text(response, self.read_timeout).await?;
+        let list: ModelList = serde_json::from_str(&body)
+            .map_err(|e| LLMError::ApiError(format!("Invalid model list: {}", e)))?;
+        let mut models: Vec<ModelInfo> = list
+            .data
+            .into_iter()
+            .map(|model| ModelInfo {
+                id: model.id,
+                context_length: model.context_length,
+            })
+            .collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(models)
+    }
+}
+
+pub struct OpenAIFactory;
+
+impl ProviderFactory for OpenAIFactory {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::api_key(ENV_OPENAI_API_KEY),
+            Setting::model(ENV_OPENAI_MODEL).with_default("gpt-3.5-turbo"),
+            Setting::endpoint(ENV_OPENAI_BASE_URL),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        let api_key = settings
+            .get(ENV_OPENAI_API_KEY)
+            .ok_or_else(|| LLMError::ConfigError("OpenAI API key not found".to_string()))?;
+
+        Ok(LLMConfig {
+            api_key,
+            model: settings.require(ENV_OPENAI_MODEL)?,
+            base_url: settings.get(ENV_OPENAI_BASE_URL),
+            ..Default::default()
+        })
+    }
+
+    fn create(
         &self,
-        system_message: String,
-        user_message: String,
-    ) -> Result<ChatStream, LLMError> {
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(&self.model)
-            .messages([
-                ChatCompletionRequestSystemMessageArgs::default()
-                    .content(system_message.as_str())
-                    .build()
-                    .map_err(|e| LLMError::InvalidRequestError(e.to_string()))?
-                    .into(),
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content(user_message.as_str())
-                    .build()
-                    .map_err(|e| LLMError::InvalidRequestError(e.to_string()))?
-                    .into(),
-            ])
-            .build()
-            .map_err(|e| LLMError::InvalidRequestError(e.to_string()))?;
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(OpenAIProvider::new(config)?))
+    }
+}
 
-        let stream = self
-            .client
-            .chat()
-            .create_stream(request)
-            .await
-            .map_err(|e| LLMError::ApiError(e.to_string()))?;
+pub struct AzureFactory;
 
-        // Convert OpenAI stream to a stream using LLMError
-        let mapped_stream = stream.map(|result| match result {
-            Ok(response) => {
-                let content = response
-                    .choices
-                    .iter()
-                    .filter_map(|choice| choice.delta.content.as_ref())
-                    .fold(String::new(), |mut acc, s| {
-                        acc.push_str(s);
-                        acc
-                    });
-                Ok(content)
-            }
-            Err(err) => Err(LLMError::ApiError(err.to_string())),
-        });
+impl ProviderFactory for AzureFactory {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    fn settings(&self) -> &'static [Setting] {
+        const SETTINGS: &[Setting] = &[
+            Setting::api_key(ENV_AZURE_OPENAI_API_KEY),
+            // Azure selects the model by deployment, so the deployment name stands in for it
+            Setting::model(ENV_AZURE_OPENAI_DEPLOYMENT),
+            // e.g. https://my-resource.openai.azure.com
+            Setting::endpoint(ENV_AZURE_OPENAI_ENDPOINT),
+            Setting::other(ENV_AZURE_OPENAI_API_VERSION),
+        ];
+        SETTINGS
+    }
+
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+        let api_key = settings
+            .get(ENV_AZURE_OPENAI_API_KEY)
+            .ok_or_else(|| LLMError::ConfigError("Azure OpenAI API key not found".to_string()))?;
+
+        Ok(LLMConfig {
+            api_key,
+            model: settings.require(ENV_AZURE_OPENAI_DEPLOYMENT)?,
+            base_url: Some(settings.require(ENV_AZURE_OPENAI_ENDPOINT)?),
+            api_version: settings.get(ENV_AZURE_OPENAI_API_VERSION),
+            ..Default::default()
+        })
+    }
 
-        Ok(Box::pin(mapped_stream))
+    fn create(
+        &self,
+        config: LLMConfig,
+        _registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        Ok(Box::new(OpenAIProvider::new(config)?))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::image::Image;
+    use crate::llm::stub_server::{self, StubResponse};
+    use crate::llm::ToolCall;
 
     #[tokio::test]
     async fn test_openai_provider_creation() {
@@ -106,10 +822,585 @@ mod tests {
             model: "gpt-3.5-turbo".to_string(),
             api_key: "test-key".to_string(),
             base_url: None,
+            ..Default::default()
         };
 
         let provider = OpenAIProvider::new(config).unwrap();
         assert_eq!(provider.name(), "openai");
         assert_eq!(provider.model(), "gpt-3.5-turbo");
     }
+
+    #[test]
+    fn test_openai_request_keeps_conversation_order() {
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            api_key: "test-key".to_string(),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let request = provider.create_request(&[
+            Message::system("be brief"),
+            Message::user("what is ls"),
+            Message::assistant("it lists files"),
+            Message::user("and ls -a?"),
+        ]);
+
+        let body = serde_json::to_value(&request).unwrap();
+        let roles: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(body["messages"][2]["content"], "it lists files");
+        // Unset generation parameters are left to the server
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn test_openai_request_generation_params() {
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            generation: GenerationParams {
+                max_tokens: Some(1000),
+                temperature: Some(0.0),
+                top_p: Some(0.5),
+                stop: vec!["END".to_string()],
+                seed: Some(42),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let body = serde_json::to_value(provider.create_request(&[Message::user("hi")])).unwrap();
+        assert_eq!(body["max_tokens"], 1000);
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["top_p"], 0.5);
+        assert_eq!(body["stop"], serde_json::json!(["END"]));
+        assert_eq!(body["seed"], 42);
+    }
+
+    #[test]
+    fn test_openai_request_for_reasoning_model() {
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "o3-mini".to_string(),
+            generation: GenerationParams {
+                max_tokens: Some(1000),
+                temperature: Some(0.0),
+                stop: vec!["END".to_string()],
+                reasoning_effort: Some("low".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let body = serde_json::to_value(
+            provider.create_request(&[Message::system("be brief"), Message::user("hi")]),
+        )
+        .unwrap();
+        assert_eq!(body["messages"][0]["role"], "developer");
+        assert_eq!(body["max_completion_tokens"], 1000);
+        assert_eq!(body["reasoning_effort"], "low");
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("stop").is_none());
+
+        // Chat models of a reasoning family take the usual parameters
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-5-chat-latest".to_string(),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+        assert!(!provider.is_reasoning_model());
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-5-mini".to_string(),
+            ..Default::default()
+        };
+        assert!(OpenAIProvider::new(config).unwrap().is_reasoning_model());
+
+        // The first reasoning models take no system prompt at all
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "o1-mini".to_string(),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+        let body =
+            serde_json::to_value(provider.create_request(&[Message::system("be brief")])).unwrap();
+        assert_eq!(body["messages"][0]["role"], "user");
+    }
+
+    #[tokio::test]
+    async fn test_openai_streams_reasoning_content() {
+        let body = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"reasoning_content\":\"Files first.\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Run ls.\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let events: Vec<StreamEvent> = OpenAIProvider::event_stream(futures::stream::iter(chunks))
+            .map(|r| r.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::ReasoningDelta("Files first.".to_string()),
+                StreamEvent::TextDelta("Run ls.".to_string()),
+                StreamEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_openai_chat_stream_against_stub() {
+        let body = concat!(
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o-2024-08-06\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o-2024-08-06\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o-2024-08-06\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\" there\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o-2024-08-06\",\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":2,\"total_tokens\":14}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let response = StubResponse::new(200, "text/event-stream", body);
+        let (base_url, server) = stub_server::serve(vec![response]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            api_key: "test-key".to_string(),
+            base_url: Some(format!("{}/v1", base_url)),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let stream = provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .unwrap();
+        let events: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Metadata {
+                    id: Some("chatcmpl-1".to_string()),
+                    model: Some("gpt-4o-2024-08-06".to_string()),
+                },
+                StreamEvent::TextDelta("Hello".to_string()),
+                StreamEvent::TextDelta(" there".to_string()),
+                StreamEvent::Stop(StopReason::EndTurn),
+                StreamEvent::Usage(Usage {
+                    input_tokens: Some(12),
+                    output_tokens: Some(2),
+                    ..Default::default()
+                }),
+            ]
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0].json()["stream_options"]["include_usage"], true);
+        assert_eq!(
+            requests[0].request_line,
+            "POST /v1/chat/completions HTTP/1.1"
+        );
+        assert_eq!(requests[0].header("authorization"), Some("Bearer test-key"));
+    }
+
+    #[tokio::test]
+    async fn test_openai_retries_without_stream_options_when_rejected() {
+        let rejection = StubResponse::new(
+            400,
+            "application/json",
+            r#"{"error":{"message":"Unrecognized request argument supplied: stream_options"}}"#,
+        );
+        let body = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n";
+        let answer = StubResponse::new(200, "text/event-stream", body);
+        let (base_url, server) = stub_server::serve(vec![rejection, answer]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "local-model".to_string(),
+            base_url: Some(format!("{}/v1", base_url)),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let stream = provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .unwrap();
+        let completion = Completion::from_stream(stream).await.unwrap();
+        assert_eq!(completion.text, "Hello");
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["stream_options"]["include_usage"], true);
+        assert!(requests[1].json().get("stream_options").is_none());
+    }
+
+    #[test]
+    fn test_openai_request_tools_and_tool_messages() {
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            tools: vec![ToolSpec {
+                name: "read_file".to_string(),
+                description: "Read a file".to_string(),
+                parameters: serde_json::json!({"type": "object"}),
+            }],
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: "read_file".to_string(),
+            arguments: String::new(),
+        };
+        let body = serde_json::to_value(provider.create_request(&[
+            Message::user("what is in Cargo.toml"),
+            Message::tool_calls("", vec![call]),
+            Message::tool_result("call_1", "[package]"),
+        ]))
+        .unwrap();
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["tools"][0]["function"]["name"], "read_file");
+        assert_eq!(body["messages"][1]["content"], serde_json::Value::Null);
+        assert_eq!(body["messages"][1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(
+            body["messages"][1]["tool_calls"][0]["function"]["arguments"],
+            "{}"
+        );
+        assert_eq!(body["messages"][2]["role"], "tool");
+        assert_eq!(body["messages"][2]["tool_call_id"], "call_1");
+        assert_eq!(body["messages"][2]["content"], "[package]");
+    }
+
+    #[test]
+    fn test_openai_request_attaches_images() {
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let image = Image::from_bytes(b"\x89PNG\r\n\x1a\n").unwrap();
+        let body = serde_json::to_value(provider.create_request(&[
+            Message::system("be brief"),
+            Message::user("what is wrong").with_images(vec![image]),
+        ]))
+        .unwrap();
+        assert_eq!(body["messages"][0]["content"], "be brief");
+        assert_eq!(
+            body["messages"][1]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "what is wrong"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_openai_request_json_schema() {
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            output_schema: Some(OutputSchema {
+                name: "answer".to_string(),
+                schema: serde_json::json!({"type": "object"}),
+            }),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let body = serde_json::to_value(provider.create_request(&[Message::user("hi")])).unwrap();
+        assert_eq!(
+            body["response_format"],
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {"name": "answer", "strict": true, "schema": {"type": "object"}},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_openai_streams_tool_call_arguments() {
+        let body = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":null,\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"type\":\"function\",\"function\":{\"name\":\"read_file\",\"arguments\":\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"path\\\":\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"a.txt\\\"}\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let chunks: Vec<Result<Vec<u8>, reqwest::Error>> = vec![Ok(body.as_bytes().to_vec())];
+
+        let events: Vec<StreamEvent> = OpenAIProvider::event_stream(futures::stream::iter(chunks))
+            .map(|r| r.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::ToolCallStart {
+                    index: 0,
+                    id: "call_1".to_string(),
+                    name: "read_file".to_string(),
+                },
+                StreamEvent::ToolCallDelta {
+                    index: 0,
+                    arguments: "{\"path\":".to_string(),
+                },
+                StreamEvent::ToolCallDelta {
+                    index: 0,
+                    arguments: "\"a.txt\"}".to_string(),
+                },
+                StreamEvent::Stop(StopReason::ToolUse),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_azure_deployment_url_and_api_key() {
+        let body = "data: {\"id\":\"chatcmpl-1\",\"model\":\"gpt-4o-2024-08-06\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"hi\"}}]}\n\ndata: [DONE]\n\n";
+        let response = StubResponse::new(200, "text/event-stream", body);
+        let (base_url, server) = stub_server::serve(vec![response]).await;
+
+        let config = LLMConfig {
+            provider: "azure".to_string(),
+            model: "gpt4o-prod".to_string(),
+            api_key: "azure-key".to_string(),
+            base_url: Some(format!("{}/", base_url)),
+            api_version: Some("2024-06-01".to_string()),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+        assert_eq!(provider.name(), "azure");
+
+        let stream = provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .unwrap();
+        let texts: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(
+            texts.last(),
+            Some(&StreamEvent::TextDelta("hi".to_string()))
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /openai/deployments/gpt4o-prod/chat/completions?api-version=2024-06-01 HTTP/1.1"
+        );
+        assert_eq!(requests[0].header("api-key"), Some("azure-key"));
+        assert_eq!(requests[0].header("authorization"), None);
+    }
+
+    #[test]
+    fn test_azure_requires_endpoint() {
+        let config = LLMConfig {
+            provider: "azure".to_string(),
+            model: "gpt4o-prod".to_string(),
+            api_key: "azure-key".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            OpenAIProvider::new(config),
+            Err(LLMError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_openai_rate_limit_keeps_retry_after() {
+        let response = StubResponse::new(
+            429,
+            "application/json",
+            "{\"error\":{\"message\":\"Rate limit reached\",\"type\":\"requests\"}}",
+        )
+        .with_header("retry-after-ms", "1500");
+        let (base_url, _server) = stub_server::serve(vec![response]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let Err(err) = provider.chat_stream(vec![Message::user("hi")]).await else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, LLMError::RateLimit { .. }));
+        assert!(err.is_retryable());
+        assert_eq!(
+            err.retry_after(),
+            Some(std::time::Duration::from_millis(1500))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_openai_quota_is_not_a_rate_limit() {
+        let response = StubResponse::new(
+            429,
+            "application/json",
+            "{\"error\":{\"message\":\"You exceeded your current quota\",\"type\":\"insufficient_quota\",\"code\":\"insufficient_quota\"}}",
+        );
+        let (base_url, _server) = stub_server::serve(vec![response]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let Err(err) = provider.chat_stream(vec![Message::user("hi")]).await else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, LLMError::QuotaExhausted(_)));
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_list_models_of_compatible_server() {
+        let body = r#"{"object": "list", "data": [{"id": "qwen2.5-coder", "object": "model", "max_model_len": 32768}, {"id": "llama3.2", "object": "model", "context_length": 131072}, {"id": "embed", "object": "model"}]}"#;
+        let (base_url, server) =
+            stub_server::serve(vec![StubResponse::new(200, "application/json", body)]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "llama3.2".to_string(),
+            base_url: Some(format!("{}/v1", base_url)),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let models = provider.list_models().await.unwrap();
+        assert_eq!(
+            models,
+            vec![
+                ModelInfo {
+                    id: "embed".to_string(),
+                    context_length: None,
+                },
+                ModelInfo {
+                    id: "llama3.2".to_string(),
+                    context_length: Some(131072),
+                },
+                ModelInfo {
+                    id: "qwen2.5-coder".to_string(),
+                    context_length: Some(32768),
+                },
+            ]
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0].request_line, "GET /v1/models HTTP/1.1");
+        // No API key is configured for the local server
+        assert_eq!(requests[0].header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn test_list_models_reports_invalid_key() {
+        let body = r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "code": "invalid_api_key"}}"#;
+        let (base_url, _server) =
+            stub_server::serve(vec![StubResponse::new(401, "application/json", body)]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            api_key: "wrong".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let result = provider.list_models().await;
+        assert!(matches!(result, Err(LLMError::Authentication(_))));
+    }
+
+    const COMPLETION: &str = r#"{"id": "chatcmpl-1", "object": "chat.completion", "model": "qwen2.5-coder", "choices": [{"index": 0, "message": {"role": "assistant", "content": null, "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "read_file", "arguments": "{\"path\": \"Cargo.toml\"}"}}]}, "finish_reason": "tool_calls"}], "usage": {"prompt_tokens": 50, "completion_tokens": 12, "total_tokens": 62}}"#;
+
+    #[tokio::test]
+    async fn test_complete_without_streaming() {
+        let (base_url, server) =
+            stub_server::serve(vec![StubResponse::new(200, "application/json", COMPLETION)]).await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "qwen2.5-coder".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let completion = provider
+            .complete(vec![Message::user("what is this project?")])
+            .await
+            .unwrap();
+        assert_eq!(
+            completion,
+            Completion {
+                text: String::new(),
+                tool_calls: vec![ToolCall {
+                    id: "call_1".to_string(),
+                    name: "read_file".to_string(),
+                    arguments: r#"{"path": "Cargo.toml"}"#.to_string(),
+                }],
+                usage: Usage {
+                    input_tokens: Some(50),
+                    output_tokens: Some(12),
+                    ..Default::default()
+                },
+                stop_reason: Some(StopReason::ToolUse),
+                id: Some("chatcmpl-1".to_string()),
+                model: Some("qwen2.5-coder".to_string()),
+                reasoning: Reasoning::default(),
+            }
+        );
+
+        let body = server.await.unwrap()[0].json();
+        assert_eq!(body["stream"], false);
+        assert!(body.get("stream_options").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_falls_back_to_whole_answer() {
+        // A server that ignores `stream: true`
+        let (base_url, _server) = stub_server::serve(vec![StubResponse::new(
+            200,
+            "application/json; charset=utf-8",
+            COMPLETION,
+        )])
+        .await;
+
+        let config = LLMConfig {
+            provider: "openai".to_string(),
+            model: "qwen2.5-coder".to_string(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = OpenAIProvider::new(config).unwrap();
+
+        let stream = provider
+            .chat_stream(vec![Message::user("what is this project?")])
+            .await
+            .unwrap();
+        let completion = Completion::from_stream(stream).await.unwrap();
+        assert_eq!(completion.tool_calls[0].name, "read_file");
+        assert_eq!(completion.stop_reason, Some(StopReason::ToolUse));
+        assert_eq!(completion.usage.output_tokens, Some(12));
+    }
 }
@@ -0,0 +1,325 @@
+//! Registry of provider factories keyed by name
+//!
+//! Each factory declares the settings it reads, turns them into an `LLMConfig` and builds
+//! the provider from it. Providers defined outside this module are added with
+//! `Registry::register`, without changes to the built-in ones.
+
+use std::collections::BTreeMap;
+
+use super::{anthropic, exec, fallback, gemini, mock, ollama, openai};
+use super::{LLMConfig, LLMError, LLMProvider};
+
+/// What a setting is for, so that error hints can point at the right one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingKind {
+    ApiKey,
+    Model,
+    Endpoint, // Where requests go, e.g. a base URL or a command
+    Other,
+}
+
+/// Setting read by a provider, e.g. `ASK_SH_OPENAI_MODEL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setting {
+    pub key: &'static str,
+    pub kind: SettingKind,
+    pub default: Option<&'static str>,
+}
+
+impl Setting {
+    pub const fn new(key: &'static str, kind: SettingKind) -> Self {
+        Self {
+            key,
+            kind,
+            default: None,
+        }
+    }
+
+    pub const fn api_key(key: &'static str) -> Self {
+        Self::new(key, SettingKind::ApiKey)
+    }
+
+    pub const fn model(key: &'static str) -> Self {
+        Self::new(key, SettingKind::Model)
+    }
+
+    pub const fn endpoint(key: &'static str) -> Self {
+        Self::new(key, SettingKind::Endpoint)
+    }
+
+    pub const fn other(key: &'static str) -> Self {
+        Self::new(key, SettingKind::Other)
+    }
+
+    pub const fn with_default(self, default: &'static str) -> Self {
+        Self {
+            default: Some(default),
+            ..self
+        }
+    }
+}
+
+/// Where settings are read from, e.g. the environment
+pub type SettingSource<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Values of the settings declared by one provider
+pub struct Settings<'a> {
+    declared: &'a [Setting],
+    source: SettingSource<'a>,
+    registry: &'a Registry,
+}
+
+impl Settings<'_> {
+    /// Value of a setting, or its default
+    pub fn get(&self, key: &str) -> Option<String> {
+        let setting = self.declared.iter().find(|s| s.key == key);
+        debug_assert!(setting.is_some(), "setting {} is not declared", key);
+        (self.source)(key).or_else(|| setting?.default.map(str::to_string))
+    }
+
+    /// Value of a setting that has to be set
+    pub fn require(&self, key: &str) -> Result<String, LLMError> {
+        self.get(key)
+            .ok_or_else(|| LLMError::ConfigError(format!("{} not found", key)))
+    }
+
+    /// Whether a setting is `true`. Anything else counts as false.
+    pub fn flag(&self, key: &str) -> bool {
+        self.get(key)
+            .is_some_and(|value| value.parse::<bool>().unwrap_or(false))
+    }
+
+    /// Configuration of another provider from the same source, e.g. a member of a fallback chain.
+    /// `pair` is a provider name, optionally followed by `/model`.
+    pub fn provider_config(&self, pair: &str) -> Result<LLMConfig, LLMError> {
+        self.registry.configure_pair(pair, self.source)
+    }
+}
+
+/// Builds providers of one kind
+pub trait ProviderFactory {
+    /// Name selecting the provider, e.g. in ASK_SH_LLM_PROVIDER
+    fn name(&self) -> &'static str;
+
+    /// Settings read by `configure`
+    fn settings(&self) -> &'static [Setting];
+
+    /// Read and validate the settings. `provider` is filled in by the registry.
+    fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError>;
+
+    /// Build the provider. The registry builds the providers it wraps, if any.
+    fn create(
+        &self,
+        config: LLMConfig,
+        registry: &Registry,
+    ) -> Result<Box<dyn LLMProvider>, LLMError>;
+}
+
+/// Provider factories keyed by name
+#[derive(Default)]
+pub struct Registry {
+    factories: BTreeMap<&'static str, Box<dyn ProviderFactory>>,
+}
+
+impl Registry {
+    /// Registry of the providers of this crate
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register(openai::OpenAIFactory);
+        registry.register(openai::AzureFactory);
+        registry.register(anthropic::AnthropicFactory);
+        registry.register(ollama::OllamaFactory);
+        registry.register(gemini::GeminiFactory);
+        registry.register(fallback::FallbackFactory);
+        registry.register(mock::MockFactory);
+        registry.register(exec::ExecFactory);
+        registry
+    }
+
+    /// Add a provider, replacing any with the same name
+    pub fn register(&mut self, factory: impl ProviderFactory + 'static) {
+        self.factories.insert(factory.name(), Box::new(factory));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ProviderFactory> {
+        self.factories.get(name).map(|factory| factory.as_ref())
+    }
+
+    fn factory(&self, name: &str) -> Result<&dyn ProviderFactory, LLMError> {
+        self.get(name)
+            .ok_or_else(|| LLMError::ConfigError(format!("Unknown provider: {}", name)))
+    }
+
+    /// Configuration of a provider from its settings
+    pub fn configure(&self, provider: &str, source: SettingSource) -> Result<LLMConfig, LLMError> {
+        let factory = self.factory(provider)?;
+        let settings = Settings {
+            declared: factory.settings(),
+            source,
+            registry: self,
+        };
+        Ok(LLMConfig {
+            provider: provider.to_string(),
+            ..factory.configure(&settings)?
+        })
+    }
+
+    /// Configuration of a provider name optionally followed by `/model`, e.g.
+    /// `ollama/qwen2.5-coder:7b`. The model may itself contain `/` or `:`.
+    pub fn configure_pair(&self, pair: &str, source: SettingSource) -> Result<LLMConfig, LLMError> {
+        match pair.split_once('/') {
+            Some((provider, model)) => Ok(LLMConfig {
+                model: model.to_string(),
+                ..self.configure(provider, source)?
+            }),
+            None => self.configure(pair, source),
+        }
+    }
+
+    pub fn create(&self, config: LLMConfig) -> Result<Box<dyn LLMProvider>, LLMError> {
+        self.factory(&config.provider)?.create(config, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ChatStream, Message, StreamEvent};
+    use async_trait::async_trait;
+    use futures::stream::{self, StreamExt};
+    use std::collections::HashMap;
+
+    /// Provider registered from outside, answering with the name of its model
+    #[derive(Debug)]
+    struct EchoProvider {
+        model: String,
+    }
+
+    #[async_trait]
+    impl LLMProvider for EchoProvider {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn model(&self) -> &str {
+            &self.model
+        }
+
+        async fn chat_stream(&self, _messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+            let text = StreamEvent::TextDelta(self.model.clone());
+            Ok(Box::pin(stream::iter(vec![Ok(text)])))
+        }
+    }
+
+    struct EchoFactory;
+
+    impl ProviderFactory for EchoFactory {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn settings(&self) -> &'static [Setting] {
+            const SETTINGS: &[Setting] =
+                &[Setting::model("ASK_SH_ECHO_MODEL").with_default("hello")];
+            SETTINGS
+        }
+
+        fn configure(&self, settings: &Settings) -> Result<LLMConfig, LLMError> {
+            Ok(LLMConfig {
+                model: settings.require("ASK_SH_ECHO_MODEL")?,
+                ..Default::default()
+            })
+        }
+
+        fn create(
+            &self,
+            config: LLMConfig,
+            _registry: &Registry,
+        ) -> Result<Box<dyn LLMProvider>, LLMError> {
+            Ok(Box::new(EchoProvider {
+                model: config.model,
+            }))
+        }
+    }
+
+    fn source(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[tokio::test]
+    async fn test_registered_provider_is_configured_and_created() {
+        let mut registry = Registry::builtin();
+        registry.register(EchoFactory);
+
+        let config = registry.configure("echo", &source(&[])).unwrap();
+        assert_eq!(config.provider, "echo");
+        let provider = registry.create(config).unwrap();
+        let mut stream = provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .unwrap();
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            StreamEvent::TextDelta("hello".to_string())
+        );
+
+        let config = registry
+            .configure("echo", &source(&[("ASK_SH_ECHO_MODEL", "hi there")]))
+            .unwrap();
+        assert_eq!(config.model, "hi there");
+    }
+
+    #[test]
+    fn test_builtin_defaults_and_validation() {
+        let registry = Registry::builtin();
+
+        let config = registry.configure("ollama", &source(&[])).unwrap();
+        assert_eq!(config.model, "llama3.2");
+        assert_eq!(
+            config.base_url.as_deref(),
+            Some(ollama::OLLAMA_DEFAULT_HOST)
+        );
+
+        let error = registry
+            .configure("ollama", &source(&[("ASK_SH_OLLAMA_NUM_CTX", "lots")]))
+            .unwrap_err();
+        assert!(error.to_string().contains("ASK_SH_OLLAMA_NUM_CTX"));
+
+        assert!(registry.configure("openai", &source(&[])).is_err());
+        assert!(matches!(
+            registry.configure("nonexistent", &source(&[])),
+            Err(LLMError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_fallback_members_are_configured_by_their_factories() {
+        let registry = Registry::builtin();
+        let vars = source(&[
+            (
+                "ASK_SH_FALLBACK_PROVIDERS",
+                "anthropic, ollama/qwen2.5-coder:7b",
+            ),
+            ("ASK_SH_ANTHROPIC_API_KEY", "key"),
+        ]);
+
+        let config = registry.configure("fallback", &vars).unwrap();
+        let members: Vec<(&str, &str)> = config
+            .fallback
+            .iter()
+            .map(|m| (m.provider.as_str(), m.model.as_str()))
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                ("anthropic", "claude-3-5-sonnet-latest"),
+                ("ollama", "qwen2.5-coder:7b")
+            ]
+        );
+        assert!(registry.create(config).is_ok());
+    }
+}
@@ -0,0 +1,390 @@
+//! Retry wrapper for transient provider failures
+//!
+//! Requests are only retried until the first piece of text or reasoning is received, so a
+//! retried answer is never printed twice.
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use std::time::Duration;
+
+use super::{ChatStream, Completion, LLMError, LLMProvider, Message, ModelInfo, StreamEvent};
+
+/// Retry settings
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    pub max_retries: u32,        // 0 disables retrying
+    pub initial_delay: Duration, // Doubled on every attempt
+    pub max_delay: Duration,     // Upper bound for both backoff and Retry-After
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(20),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before the given retry (starting at 0), or None if the error should not be retried
+    fn delay_for(&self, attempt: u32, error: &LLMError) -> Option<Duration> {
+        if attempt >= self.max_retries || !error.is_retryable() {
+            return None;
+        }
+        match error.retry_after() {
+            // Waiting longer than max_delay in an interactive shell is worse than retrying early
+            Some(retry_after) => Some(retry_after.min(self.max_delay)),
+            None => {
+                let backoff = self
+                    .initial_delay
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(self.max_delay);
+                // Jitter between half and the full backoff
+                let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+                Some(backoff.mul_f64(jitter))
+            }
+        }
+    }
+}
+
+/// Read the delay requested by `retry-after-ms` or `retry-after` (in seconds) headers.
+/// Negative, infinite or overflowing values are ignored; `RetryConfig` caps the others.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str, unit: f64| {
+        let value = headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()?;
+        Duration::try_from_secs_f64(value * unit).ok()
+    };
+    header("retry-after-ms", 0.001).or_else(|| header("retry-after", 1.0))
+}
+
+/// Provider wrapper that retries transient failures with exponential backoff
+#[derive(Debug)]
+pub struct RetryProvider<P: LLMProvider> {
+    inner: P,
+    config: RetryConfig,
+    debug: bool, // Report each retry to stderr
+}
+
+impl<P: LLMProvider> RetryProvider<P> {
+    pub fn new(inner: P, config: RetryConfig, debug: bool) -> Self {
+        Self {
+            inner,
+            config,
+            debug,
+        }
+    }
+
+    async fn try_chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        // Errors before the first token (e.g. an overloaded event) are also worth a retry
+        wait_for_text(self.inner.chat_stream(messages).await?).await
+    }
+
+    /// Wait before the next attempt, or return the error if it should not be retried
+    async fn backoff(&self, attempt: &mut u32, error: LLMError) -> Result<(), LLMError> {
+        let Some(delay) = self.config.delay_for(*attempt, &error) else {
+            return Err(error);
+        };
+        *attempt += 1;
+        if self.debug {
+            eprintln!(
+                "retry {}/{} in {:.1}s after error: {}",
+                attempt,
+                self.config.max_retries,
+                delay.as_secs_f64(),
+                error
+            );
+        }
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+}
+
+/// Hold back the events of a stream until the first text or reasoning arrives, so that
+/// an error received before it can be returned as the error of the whole request
+pub async fn wait_for_text(mut stream: ChatStream) -> Result<ChatStream, LLMError> {
+    let mut received = Vec::new();
+    loop {
+        match stream.next().await {
+            Some(event) => {
+                let is_text = matches!(
+                    event,
+                    Ok(StreamEvent::TextDelta(_) | StreamEvent::ReasoningDelta(_))
+                );
+                received.push(event?);
+                if is_text {
+                    break;
+                }
+            }
+            // No text at all, e.g. an answer that only calls tools. The stream must not be polled again.
+            None => return Ok(Box::pin(stream::iter(received.into_iter().map(Ok)))),
+        }
+    }
+    Ok(Box::pin(
+        stream::iter(received.into_iter().map(Ok)).chain(stream),
+    ))
+}
+
+#[async_trait]
+impl<P: LLMProvider> LLMProvider for RetryProvider<P> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    async fn chat_stream(&self, messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+        let mut attempt = 0;
+        loop {
+            match self.try_chat_stream(messages.clone()).await {
+                Ok(stream) => return Ok(stream),
+                Err(error) => self.backoff(&mut attempt, error).await?,
+            }
+        }
+    }
+
+    async fn complete(&self, messages: Vec<Message>) -> Result<Completion, LLMError> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.complete(messages.clone()).await {
+                Ok(completion) => return Ok(completion),
+                Err(error) => self.backoff(&mut attempt, error).await?,
+            }
+        }
+    }
+
+    fn supports_images(&self) -> bool {
+        self.inner.supports_images()
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LLMError> {
+        self.inner.list_models().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::sync::Mutex;
+
+    /// Result of one call: either an error, or the items of the stream
+    type ScriptedCall = Result<Vec<Result<StreamEvent, LLMError>>, LLMError>;
+
+    /// Provider returning scripted results, one per call
+    #[derive(Debug)]
+    struct ScriptedProvider {
+        results: Mutex<Vec<ScriptedCall>>,
+    }
+
+    #[async_trait]
+    impl LLMProvider for ScriptedProvider {
+        fn name(&self) -> &'static str {
+            "scripted"
+        }
+
+        fn model(&self) -> &str {
+            "scripted"
+        }
+
+        async fn chat_stream(&self, _messages: Vec<Message>) -> Result<ChatStream, LLMError> {
+            let items = self.results.lock().unwrap().remove(0)?;
+            // Like the streams of real providers, it panics if polled after its end
+            Ok(Box::pin(stream::unfold(
+                items.into_iter(),
+                |mut items| async move { items.next().map(|item| (item, items)) },
+            )))
+        }
+    }
+
+    fn rate_limited() -> LLMError {
+        LLMError::RateLimit {
+            message: "rate limited".to_string(),
+            retry_after: Some(Duration::from_millis(1)),
+        }
+    }
+
+    fn fast_config(max_retries: u32) -> RetryConfig {
+        RetryConfig {
+            max_retries,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_success() {
+        let provider = ScriptedProvider {
+            results: Mutex::new(vec![
+                Err(rate_limited()),
+                Ok(vec![
+                    Ok(StreamEvent::Metadata {
+                        id: None,
+                        model: None,
+                    }),
+                    Err(LLMError::Overloaded {
+                        message: "overloaded".to_string(),
+                        retry_after: None,
+                    }),
+                ]),
+                Ok(vec![
+                    Ok(StreamEvent::TextDelta("hello".to_string())),
+                    Ok(StreamEvent::TextDelta(" world".to_string())),
+                ]),
+            ]),
+        };
+        let provider = RetryProvider::new(provider, fast_config(2), false);
+
+        let stream = provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .unwrap();
+        let events: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::TextDelta("hello".to_string()),
+                StreamEvent::TextDelta(" world".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_answer_without_text() {
+        let call = StreamEvent::ToolCallStart {
+            index: 0,
+            id: "call_1".to_string(),
+            name: "read_file".to_string(),
+        };
+        let provider = ScriptedProvider {
+            results: Mutex::new(vec![Ok(vec![Ok(call.clone())])]),
+        };
+        let provider = RetryProvider::new(provider, fast_config(2), false);
+
+        let stream = provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .unwrap();
+        let events: Vec<StreamEvent> = stream.map(|r| r.unwrap()).collect().await;
+        assert_eq!(events, vec![call]);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_non_transient_errors() {
+        let provider = ScriptedProvider {
+            results: Mutex::new(vec![
+                Err(LLMError::Authentication("invalid x-api-key".to_string())),
+                Ok(vec![Ok(StreamEvent::TextDelta("unreachable".to_string()))]),
+            ]),
+        };
+        let provider = RetryProvider::new(provider, fast_config(2), false);
+
+        let result = provider.chat_stream(vec![Message::user("hi")]).await;
+        assert!(matches!(result, Err(LLMError::Authentication(_))));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_retries() {
+        let provider = ScriptedProvider {
+            results: Mutex::new(vec![
+                Err(rate_limited()),
+                Ok(vec![Ok(StreamEvent::TextDelta("ok".to_string()))]),
+            ]),
+        };
+        let provider = RetryProvider::new(provider, fast_config(0), false);
+
+        assert!(provider
+            .chat_stream(vec![Message::user("hi")])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_delay_honours_retry_after_and_cap() {
+        let config = RetryConfig {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        };
+        let with_retry_after = |secs| LLMError::RateLimit {
+            message: String::new(),
+            retry_after: Some(Duration::from_secs(secs)),
+        };
+        assert_eq!(
+            config.delay_for(0, &with_retry_after(2)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            config.delay_for(0, &with_retry_after(60)),
+            Some(Duration::from_secs(5))
+        );
+
+        let network = LLMError::NetworkError("reset".to_string());
+        let delay = config.delay_for(2, &network).unwrap();
+        assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        assert_eq!(config.delay_for(3, &network), None);
+    }
+
+    #[test]
+    fn test_retry_after_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(250)));
+
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_invalid_retry_after_headers_are_ignored() {
+        for value in ["inf", "NaN", "-1", "1e30", "soon"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after", HeaderValue::from_static(value));
+            assert_eq!(retry_after(&headers), None, "{}", value);
+
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after-ms", HeaderValue::from_static(value));
+            assert_eq!(retry_after(&headers), None, "{}", value);
+        }
+
+        // A huge but valid delay is cut down to the maximum delay
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("86400000"));
+        let error = LLMError::RateLimit {
+            message: String::new(),
+            retry_after: retry_after(&headers),
+        };
+        let config = RetryConfig::default();
+        assert_eq!(config.delay_for(0, &error), Some(config.max_delay));
+    }
+
+    #[tokio::test]
+    async fn test_complete_is_retried() {
+        let provider = ScriptedProvider {
+            results: Mutex::new(vec![
+                Err(rate_limited()),
+                Ok(vec![
+                    Ok(StreamEvent::TextDelta("hello".to_string())),
+                    Ok(StreamEvent::TextDelta(" world".to_string())),
+                ]),
+            ]),
+        };
+        let provider = RetryProvider::new(provider, fast_config(1), false);
+
+        let completion = provider.complete(vec![Message::user("hi")]).await.unwrap();
+        assert_eq!(completion.text, "hello world");
+    }
+}
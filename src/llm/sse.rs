@@ -0,0 +1,233 @@
+//! Buffered decoder for server-sent events (text/event-stream)
+//!
+//! Network chunks may end anywhere, including in the middle of a line or a multi-byte
+//! UTF-8 character, so bytes are buffered until a full line is available.
+
+use futures::stream::{self, Stream, StreamExt};
+
+/// Event dispatched by the decoder
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: Option<String>, // Value of the `event:` field, if any
+    pub data: String,          // `data:` fields joined by newlines
+}
+
+/// Incremental SSE decoder
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: String,
+    has_data: bool,
+    started: bool,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a chunk of bytes and return the events completed by it
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        self.strip_bom();
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buffer.len() {
+            let end_of_line = match self.buffer[i] {
+                b'\n' => Some(i + 1),
+                // A trailing \r may be the first half of \r\n, so wait for more bytes
+                b'\r' if i + 1 == self.buffer.len() => break,
+                b'\r' if self.buffer[i + 1] == b'\n' => Some(i + 2),
+                b'\r' => Some(i + 1),
+                _ => None,
+            };
+            if let Some(next) = end_of_line {
+                let line = String::from_utf8_lossy(&self.buffer[start..i]).to_string();
+                events.extend(self.process_line(&line));
+                start = next;
+                i = next;
+            } else {
+                i += 1;
+            }
+        }
+        self.buffer.drain(..start);
+        events
+    }
+
+    /// Flush the decoder at the end of the stream
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buffer.is_empty() {
+            let line = String::from_utf8_lossy(&self.buffer).to_string();
+            self.buffer.clear();
+            if let Some(event) = self.process_line(&line) {
+                return Some(event);
+            }
+        }
+        // Servers do not always terminate the last event with a blank line
+        self.process_line("")
+    }
+
+    fn strip_bom(&mut self) {
+        const BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+        if self.started || (self.buffer.len() < BOM.len() && BOM.starts_with(&self.buffer)) {
+            return; // Already checked, or may still become a BOM
+        }
+        self.started = true;
+        if self.buffer.starts_with(BOM) {
+            self.buffer.drain(..BOM.len());
+        }
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None; // comment, e.g. keep-alive
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            _ => {} // `id`, `retry` and unknown fields are not used by any provider
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if !self.has_data {
+            return None;
+        }
+        self.has_data = false;
+        Some(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data),
+        })
+    }
+}
+
+/// Decode a byte stream, such as `reqwest::Response::bytes_stream()`, into SSE events
+pub fn sse_stream<S, B, E>(bytes: S) -> impl Stream<Item = Result<SseEvent, E>>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    stream::unfold(
+        (bytes, SseDecoder::new(), false),
+        |(mut bytes, mut decoder, finished)| async move {
+            if finished {
+                return None;
+            }
+            match bytes.next().await {
+                Some(Ok(chunk)) => {
+                    let events: Vec<Result<SseEvent, E>> =
+                        decoder.push(chunk.as_ref()).into_iter().map(Ok).collect();
+                    Some((events, (bytes, decoder, false)))
+                }
+                Some(Err(e)) => Some((vec![Err(e)], (bytes, decoder, true))),
+                None => {
+                    let events = decoder.finish().into_iter().map(Ok).collect();
+                    Some((events, (bytes, decoder, true)))
+                }
+            }
+        },
+    )
+    .flat_map(stream::iter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_byte_by_byte(input: &[u8]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events = Vec::new();
+        for byte in input {
+            events.extend(decoder.push(&[*byte]));
+        }
+        events.extend(decoder.finish());
+        events
+    }
+
+    #[test]
+    fn test_events_fed_one_byte_at_a_time() {
+        let input = "event: message_start\ndata: {\"a\":1}\n\n: keep-alive\n\nevent: delta\ndata: {\"b\":2}\n\n";
+        let events = decode_byte_by_byte(input.as_bytes());
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message_start".to_string()),
+                    data: "{\"a\":1}".to_string(),
+                },
+                SseEvent {
+                    event: Some("delta".to_string()),
+                    data: "{\"b\":2}".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_utf8_is_not_garbled() {
+        let input = "data: こんにちは、世界\n\ndata: 🦀\n\n";
+        let events = decode_byte_by_byte(input.as_bytes());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, "こんにちは、世界");
+        assert_eq!(events[1].data, "🦀");
+    }
+
+    #[test]
+    fn test_multi_line_data_and_crlf() {
+        let input = "data: first\r\ndata:second\r\ndata\r\n\r\n";
+        let events = decode_byte_by_byte(input.as_bytes());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, None);
+        assert_eq!(events[0].data, "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_event_without_data_is_not_dispatched() {
+        let events = decode_byte_by_byte(b"event: ping\n\ndata: x\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, None);
+        assert_eq!(events[0].data, "x");
+    }
+
+    #[test]
+    fn test_last_event_without_blank_line() {
+        let events = decode_byte_by_byte("\u{feff}data: [DONE]".as_bytes());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream_over_chunks() {
+        let chunks: Vec<Result<Vec<u8>, ()>> = "data: é\n\ndata: ok\n\n"
+            .as_bytes()
+            .chunks(1)
+            .map(|c| Ok(c.to_vec()))
+            .collect();
+        let events: Vec<SseEvent> = sse_stream(stream::iter(chunks))
+            .map(|e| e.unwrap())
+            .collect()
+            .await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, "é");
+        assert_eq!(events[1].data, "ok");
+    }
+}
@@ -0,0 +1,131 @@
+//! Minimal HTTP server used to test providers against canned responses
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Response replayed by the stub server
+#[derive(Debug, Clone)]
+pub struct StubResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub chunks: Vec<Vec<u8>>, // Written and flushed one by one
+}
+
+impl StubResponse {
+    pub fn new(status: u16, content_type: &str, body: &str) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            chunks: vec![body.as_bytes().to_vec()],
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_chunks(mut self, chunks: Vec<&str>) -> Self {
+        self.chunks = chunks.iter().map(|c| c.as_bytes().to_vec()).collect();
+        self
+    }
+}
+
+/// Request received by the stub server
+#[derive(Debug)]
+pub struct RecordedRequest {
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// Start a server on a random local port that answers one connection per response, in order.
+/// Returns the base URL and a handle resolving to the requests it received.
+pub async fn serve(responses: Vec<StubResponse>) -> (String, JoinHandle<Vec<RecordedRequest>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            requests.push(read_request(&mut socket).await);
+            write_response(&mut socket, &response).await;
+        }
+        requests
+    });
+
+    (base_url, handle)
+}
+
+async fn read_request(socket: &mut TcpStream) -> RecordedRequest {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = socket.read(&mut chunk).await.unwrap();
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if n == 0 {
+            panic!("connection closed before request headers were complete");
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+
+    RecordedRequest {
+        request_line,
+        headers,
+        body: String::from_utf8_lossy(&buffer[header_end..]).to_string(),
+    }
+}
+
+async fn write_response(socket: &mut TcpStream, response: &StubResponse) {
+    let mut head = format!("HTTP/1.1 {} Stub\r\nConnection: close\r\n", response.status);
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str("\r\n");
+    socket.write_all(head.as_bytes()).await.unwrap();
+
+    for chunk in &response.chunks {
+        socket.write_all(chunk).await.unwrap();
+        socket.flush().await.unwrap();
+        tokio::task::yield_now().await;
+    }
+    socket.shutdown().await.ok();
+}
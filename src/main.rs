@@ -6,20 +6,40 @@ use std::{
         self,
         consts::{ARCH, OS},
     },
-    error::Error,
-    io::{self, BufRead},
+    io::{self, BufRead, IsTerminal},
+    path::PathBuf,
     process,
+    str::FromStr,
+    time::{Duration, Instant},
 };
+use tokio::sync::mpsc;
 
+mod budget;
+mod compare;
 mod llm;
 mod prompts;
+mod suggestions;
+mod tools;
 
-use llm::{create_provider, LLMConfig, LLMError, LLMProvider};
+use llm::cache::{CacheConfig, CacheProvider, ResponseCache};
+use llm::fallback::FallbackProvider;
+use llm::http::HttpConfig;
+use llm::image::Image;
+use llm::registry::{Registry, SettingKind};
+use llm::retry::{RetryConfig, RetryProvider};
+use llm::{
+    Completion, CompletionBuilder, GenerationParams, LLMConfig, LLMError, LLMProvider, Message,
+    StopReason, StreamEvent, ToolCall, Usage,
+};
+use suggestions::{ProseExtractor, Suggestion};
+use tools::BuiltinTool;
 
 // args
 const ARG_DEBUG: &str = "--debug_ask_sh";
 const ARG_NO_PANE: &str = "--no_pane";
 const ARG_NO_SUGGEST: &str = "--no_suggest";
+const ARG_NO_CACHE: &str = "--no_cache";
+const ARG_COMPARE: &str = "--compare";
 const ARG_VERSION: &str = "--version";
 const ARG_VERSION_SHORT: &str = "-v";
 
@@ -27,67 +47,275 @@ const ARG_STRINGS: &[&str] = &[
     ARG_DEBUG,
     ARG_NO_PANE,
     ARG_NO_SUGGEST,
+    ARG_NO_CACHE,
+    ARG_COMPARE,
     ARG_VERSION,
     ARG_VERSION_SHORT,
 ];
 
 // special arg
 const ARG_INIT: &str = "--init";
+const ARG_MODELS: &str = "models";
+const ARG_PROVIDER: &str = "--provider";
+const ARG_CACHE: &str = "cache";
+const ARG_CACHE_CLEAR: &str = "clear";
+const ARG_IMAGE: &str = "--image"; // Followed by the path of the image
 
 // env
 const ENV_DEBUG: &str = "ASK_SH_DEBUG";
 const ENV_NO_PANE: &str = "ASK_SH_NO_PANE";
 const ENV_NO_SUGGEST: &str = "ASK_SH_NO_SUGGEST";
+const ENV_MAX_RETRIES: &str = "ASK_SH_MAX_RETRIES";
+const ENV_RETRY_INITIAL_DELAY_MS: &str = "ASK_SH_RETRY_INITIAL_DELAY_MS";
+const ENV_RETRY_MAX_DELAY_MS: &str = "ASK_SH_RETRY_MAX_DELAY_MS";
+const ENV_TOOLS: &str = "ASK_SH_TOOLS";
+const ENV_MAX_TOOL_ROUNDS: &str = "ASK_SH_MAX_TOOL_ROUNDS";
+const ENV_STRUCTURED_OUTPUT: &str = "ASK_SH_STRUCTURED_OUTPUT";
+const ENV_CHECK_MODEL: &str = "ASK_SH_CHECK_MODEL";
+const ENV_NO_STREAM: &str = "ASK_SH_NO_STREAM";
+const ENV_HIDE_REASONING: &str = "ASK_SH_HIDE_REASONING";
+const ENV_COMPARE_PROVIDERS: &str = "ASK_SH_COMPARE_PROVIDERS";
+
+// Cache of answers
+const ENV_CACHE: &str = "ASK_SH_CACHE";
+const ENV_CACHE_DIR: &str = "ASK_SH_CACHE_DIR";
+const ENV_CACHE_TTL_SECS: &str = "ASK_SH_CACHE_TTL_SECS";
+const ENV_CACHE_MAX_MB: &str = "ASK_SH_CACHE_MAX_MB";
+
+// HTTP client settings, shared by all providers
+const ENV_HTTP_PROXY: &str = "ASK_SH_HTTP_PROXY";
+const ENV_NO_PROXY: &str = "ASK_SH_NO_PROXY";
+const ENV_CA_BUNDLE: &str = "ASK_SH_CA_BUNDLE";
+const ENV_CLIENT_CERT: &str = "ASK_SH_CLIENT_CERT";
+const ENV_CLIENT_KEY: &str = "ASK_SH_CLIENT_KEY";
+const ENV_CONNECT_TIMEOUT_MS: &str = "ASK_SH_CONNECT_TIMEOUT_MS";
+const ENV_READ_TIMEOUT_MS: &str = "ASK_SH_READ_TIMEOUT_MS";
 
 // LLM provider settings
 const ENV_LLM_PROVIDER: &str = "ASK_SH_LLM_PROVIDER";
-const ENV_OPENAI_API_KEY: &str = "ASK_SH_OPENAI_API_KEY";
-const ENV_OPENAI_MODEL: &str = "ASK_SH_OPENAI_MODEL";
-const ENV_OPENAI_BASE_URL: &str = "ASK_SH_OPENAI_BASE_URL";
-const ENV_ANTHROPIC_API_KEY: &str = "ASK_SH_ANTHROPIC_API_KEY";
-const ENV_ANTHROPIC_MODEL: &str = "ASK_SH_ANTHROPIC_MODEL";
+
+// Generation parameters, read from ASK_SH_<PROVIDER>_<NAME> or else ASK_SH_<NAME>
+const GEN_MAX_TOKENS: &str = "MAX_TOKENS";
+const GEN_TEMPERATURE: &str = "TEMPERATURE";
+const GEN_TOP_P: &str = "TOP_P";
+const GEN_STOP: &str = "STOP";
+const GEN_SEED: &str = "SEED";
+const GEN_REASONING_EFFORT: &str = "REASONING_EFFORT";
+const GEN_THINKING_BUDGET: &str = "THINKING_BUDGET";
+
+const REASONING_EFFORTS: &[&str] = &["minimal", "low", "medium", "high"];
+
+// Size of the model's context in tokens, read like the generation parameters
+const CONTEXT_WINDOW: &str = "CONTEXT_WINDOW";
 
 fn get_llm_config() -> Result<LLMConfig, LLMError> {
     dotenv().ok();
 
     // Select provider (default is OpenAI)
     let provider = env::var(ENV_LLM_PROVIDER).unwrap_or_else(|_| "openai".to_string());
+    get_provider_config(&provider)
+}
 
-    match provider.as_str() {
-        "openai" => {
-            let api_key = env::var(ENV_OPENAI_API_KEY)
-                .map_err(|_| LLMError::ConfigError("OpenAI API key not found".to_string()))?;
+/// Configuration of a provider, optionally followed by `/model`
+fn get_provider_config(pair: &str) -> Result<LLMConfig, LLMError> {
+    let config = Registry::builtin().configure_pair(pair, &|key| env::var(key).ok())?;
+    with_common_settings(config)
+}
 
-            let model = env::var(ENV_OPENAI_MODEL).unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
+/// Add the settings shared by all providers, also to the members of a fallback chain
+fn with_common_settings(config: LLMConfig) -> Result<LLMConfig, LLMError> {
+    let generation = get_generation_params(&config.provider)?;
+    let tools = get_enabled_tools()?.iter().map(BuiltinTool::spec).collect();
+    let output_schema = get_env_flag(ENV_STRUCTURED_OUTPUT).then(suggestions::output_schema);
+    let http = get_http_config()?;
+    let fallback = config
+        .fallback
+        .into_iter()
+        .map(with_common_settings)
+        .collect::<Result<Vec<_>, LLMError>>()?;
+    Ok(LLMConfig {
+        generation,
+        tools,
+        output_schema,
+        http,
+        fallback,
+        ..config
+    })
+}
 
-            let base_url = env::var(ENV_OPENAI_BASE_URL).ok();
+fn get_http_config() -> Result<HttpConfig, LLMError> {
+    let path = |key: &str| {
+        env::var_os(key)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    let client_cert = path(ENV_CLIENT_CERT);
+    let client_key = path(ENV_CLIENT_KEY);
+    if client_cert.is_some() != client_key.is_some() {
+        return Err(LLMError::ConfigError(format!(
+            "{} and {} must be set together",
+            ENV_CLIENT_CERT, ENV_CLIENT_KEY
+        )));
+    }
+    let millis = |key: &str| -> Result<Option<Duration>, LLMError> {
+        Ok(get_env_parsed::<u64>(key)?.map(Duration::from_millis))
+    };
+    Ok(HttpConfig {
+        proxy: env::var(ENV_HTTP_PROXY).ok().filter(|v| !v.is_empty()),
+        no_proxy: env::var(ENV_NO_PROXY).ok().filter(|v| !v.is_empty()),
+        ca_bundle: path(ENV_CA_BUNDLE),
+        client_cert,
+        client_key,
+        connect_timeout: millis(ENV_CONNECT_TIMEOUT_MS)?,
+        read_timeout: millis(ENV_READ_TIMEOUT_MS)?,
+    })
+}
 
-            Ok(LLMConfig {
-                provider,
-                api_key,
-                model,
-                base_url,
+/// Tools allowed in ASK_SH_TOOLS, separated by commas. None are offered by default.
+fn get_enabled_tools() -> Result<Vec<BuiltinTool>, LLMError> {
+    let Ok(value) = env::var(ENV_TOOLS) else {
+        return Ok(Vec::new());
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            BuiltinTool::from_name(name).ok_or_else(|| {
+                let names: Vec<&str> = tools::ALL_TOOLS.iter().map(|t| t.name()).collect();
+                LLMError::ConfigError(format!(
+                    "Unknown tool in {}: {} (available: {})",
+                    ENV_TOOLS,
+                    name,
+                    names.join(", ")
+                ))
             })
-        }
-        "anthropic" => {
-            let api_key = env::var(ENV_ANTHROPIC_API_KEY)
-                .map_err(|_| LLMError::ConfigError("Anthropic API key not found".to_string()))?;
+        })
+        .collect()
+}
 
-            let model = env::var(ENV_ANTHROPIC_MODEL)
-                .unwrap_or_else(|_| "claude-3-5-sonnet-latest".to_string());
+/// Parse an environment variable, if set
+fn get_env_parsed<T: FromStr>(key: &str) -> Result<Option<T>, LLMError> {
+    match env::var(key) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| LLMError::ConfigError(format!("Invalid value for {}: {}", key, value))),
+        Err(_) => Ok(None),
+    }
+}
 
-            Ok(LLMConfig {
-                provider,
-                api_key,
-                model,
-                base_url: None, // Anthropic does not support custom endpoints
-            })
+/// Variable of a setting that can be made for one provider only. The provider-specific
+/// variable wins, e.g. ASK_SH_ANTHROPIC_MAX_TOKENS over ASK_SH_MAX_TOKENS.
+fn provider_env_key(provider: &str, name: &str) -> String {
+    let provider_key = format!("ASK_SH_{}_{}", provider.to_uppercase(), name);
+    if env::var(&provider_key).is_ok() {
+        provider_key
+    } else {
+        format!("ASK_SH_{}", name)
+    }
+}
+
+fn get_generation_params(provider: &str) -> Result<GenerationParams, LLMError> {
+    let key = |name: &str| provider_env_key(provider, name);
+
+    let temperature = get_env_parsed::<f32>(&key(GEN_TEMPERATURE))?;
+    if let Some(temperature) = temperature.filter(|t| !(0.0..=2.0).contains(t)) {
+        return Err(LLMError::ConfigError(format!(
+            "{} must be between 0 and 2: {}",
+            key(GEN_TEMPERATURE),
+            temperature
+        )));
+    }
+    let top_p = get_env_parsed::<f32>(&key(GEN_TOP_P))?;
+    if let Some(top_p) = top_p.filter(|p| !(0.0..=1.0).contains(p)) {
+        return Err(LLMError::ConfigError(format!(
+            "{} must be between 0 and 1: {}",
+            key(GEN_TOP_P),
+            top_p
+        )));
+    }
+
+    // Stop sequences are separated by commas
+    let stop = env::var(key(GEN_STOP))
+        .map(|value| {
+            value
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let reasoning_effort = env::var(key(GEN_REASONING_EFFORT)).ok();
+    if let Some(effort) = reasoning_effort
+        .as_ref()
+        .filter(|e| !REASONING_EFFORTS.contains(&e.as_str()))
+    {
+        return Err(LLMError::ConfigError(format!(
+            "{} must be one of {}: {}",
+            key(GEN_REASONING_EFFORT),
+            REASONING_EFFORTS.join(", "),
+            effort
+        )));
+    }
+
+    Ok(GenerationParams {
+        max_tokens: get_env_parsed(&key(GEN_MAX_TOKENS))?,
+        temperature,
+        top_p,
+        stop,
+        seed: get_env_parsed(&key(GEN_SEED))?,
+        reasoning_effort,
+        thinking_budget: get_env_parsed(&key(GEN_THINKING_BUDGET))?,
+    })
+}
+
+fn get_retry_config() -> Result<RetryConfig, LLMError> {
+    dotenv().ok();
+
+    fn parse<T: FromStr>(key: &str) -> Result<Option<T>, LLMError> {
+        match env::var(key) {
+            Ok(value) => value.parse::<T>().map(Some).map_err(|_| {
+                LLMError::ConfigError(format!("{} must be a non-negative integer: {}", key, value))
+            }),
+            Err(_) => Ok(None),
         }
-        _ => Err(LLMError::ConfigError(format!(
-            "Unknown provider: {}",
-            provider
-        ))),
     }
+
+    let mut config = RetryConfig::default();
+    if let Some(max_retries) = parse::<u32>(ENV_MAX_RETRIES)? {
+        config.max_retries = max_retries;
+    }
+    if let Some(ms) = parse::<u64>(ENV_RETRY_INITIAL_DELAY_MS)? {
+        config.initial_delay = Duration::from_millis(ms);
+    }
+    if let Some(ms) = parse::<u64>(ENV_RETRY_MAX_DELAY_MS)? {
+        config.max_delay = Duration::from_millis(ms);
+    }
+    Ok(config)
+}
+
+fn get_cache_config() -> Result<CacheConfig, LLMError> {
+    dotenv().ok();
+
+    let dir = match env::var_os(ENV_CACHE_DIR).filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => CacheConfig::default_dir().ok_or_else(|| {
+            LLMError::ConfigError(format!(
+                "Cache directory not found. Set {} or HOME.",
+                ENV_CACHE_DIR
+            ))
+        })?,
+    };
+    let ttl_secs = get_env_parsed::<u64>(ENV_CACHE_TTL_SECS)?.unwrap_or(7 * 24 * 60 * 60);
+    let max_mb = get_env_parsed::<u64>(ENV_CACHE_MAX_MB)?.unwrap_or(50);
+    Ok(CacheConfig {
+        dir,
+        ttl: Duration::from_secs(ttl_secs),
+        max_bytes: max_mb.saturating_mul(1024 * 1024),
+    })
 }
 
 fn get_env_flag(key: &str) -> bool {
@@ -105,33 +333,676 @@ struct UserInfo {
     // TODO: add distro info if linux
 }
 
+/// Print the answer as it streams in and collect it.
+/// A structured answer is JSON, of which only the prose is printed.
+/// The reasoning before the answer is printed dimmed, unless it is hidden. It is never part
+/// of the text, so no commands are taken from it.
+async fn receive_answer(
+    provider: &impl LLMProvider,
+    messages: Vec<Message>,
+    structured_output: bool,
+    streaming: bool,
+    show_reasoning: bool,
+    started_at: Instant,
+    time_to_first_token: &mut Option<Duration>,
+) -> Result<Completion, LLMError> {
+    let mut stream = if streaming {
+        provider.chat_stream(messages).await?
+    } else {
+        // The whole answer is printed at once when it has arrived
+        provider.complete(messages).await?.into_stream()
+    };
+
+    let mut prose = structured_output.then(ProseExtractor::default);
+    let mut answer = CompletionBuilder::default();
+    let dim = io::stderr().is_terminal();
+    let mut printed_text = false;
+    let mut in_reasoning = false; // Reasoning has been printed, and the answer not yet
+    while let Some(result) = stream.next().await {
+        let event = match result {
+            Ok(event) => event,
+            Err(err) => {
+                // end the partial answer so that the error is reported on its own line
+                if printed_text || in_reasoning {
+                    eprintln!();
+                }
+                return Err(err);
+            }
+        };
+        match &event {
+            StreamEvent::ReasoningDelta(content) => {
+                time_to_first_token.get_or_insert_with(|| started_at.elapsed());
+                if show_reasoning {
+                    in_reasoning = true;
+                    if dim {
+                        eprint!("\x1b[2m{}\x1b[0m", content);
+                    } else {
+                        eprint!("{}", content);
+                    }
+                }
+            }
+            StreamEvent::TextDelta(content) => {
+                time_to_first_token.get_or_insert_with(|| started_at.elapsed());
+                if in_reasoning {
+                    in_reasoning = false;
+                    eprint!("\n\n");
+                }
+                printed_text = true;
+                match prose.as_mut() {
+                    Some(prose) => eprint!("{}", prose.push(content)),
+                    None => eprint!("{}", content),
+                }
+            }
+            _ => {}
+        }
+        answer.push(event);
+    }
+    Ok(answer.finish())
+}
+
+/// Run the tools called by the model, showing each call to the user
+async fn run_tool_calls(
+    calls: &[ToolCall],
+    allowed: &[BuiltinTool],
+    debug_mode: bool,
+) -> Vec<Message> {
+    let mut results = Vec::new();
+    for call in calls {
+        eprintln!("🔧 {} {}", call.name, call.arguments.trim());
+        // The model may ask for a tool it was not offered
+        let result = match BuiltinTool::from_name(&call.name).filter(|t| allowed.contains(t)) {
+            Some(tool) => tool.run(call).await,
+            None => Err(format!("Tool not available: {}", call.name)),
+        };
+        let content = match result {
+            Ok(output) => output,
+            Err(error) => {
+                eprintln!("   {}", error);
+                format!("Error: {}", error)
+            }
+        };
+        if debug_mode {
+            eprintln!("tool_output_bytes: {}", content.len());
+        }
+        results.push(Message::tool_result(call.id.clone(), content));
+    }
+    results
+}
+
+/// Providers and models listed in ASK_SH_COMPARE_PROVIDERS
+fn get_compare_pairs() -> Vec<String> {
+    dotenv().ok();
+    env::var(ENV_COMPARE_PROVIDERS)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Tokens left for the terminal output once the rest of the prompt and the answer are
+/// accounted for, in the smallest context of the models that may be asked
+fn get_pane_budget(
+    compare_mode: bool,
+    prompt_tokens: usize,
+    debug_mode: bool,
+) -> Result<usize, LLMError> {
+    let configs = if compare_mode {
+        get_compare_pairs()
+            .iter()
+            .map(|pair| get_provider_config(pair))
+            .collect::<Result<Vec<_>, LLMError>>()?
+    } else {
+        vec![get_llm_config()?]
+    };
+    // Any member of a fallback chain may answer
+    let configs: Vec<LLMConfig> = configs
+        .into_iter()
+        .flat_map(|config| {
+            if config.fallback.is_empty() {
+                vec![config]
+            } else {
+                config.fallback
+            }
+        })
+        .collect();
+
+    let mut pane_budget = usize::MAX;
+    for config in configs {
+        let window =
+            match get_env_parsed::<usize>(&provider_env_key(&config.provider, CONTEXT_WINDOW))? {
+                Some(window) => window,
+                None => config.num_ctx.map_or_else(
+                    || budget::context_window(&config.provider, &config.model),
+                    |n| n as usize,
+                ),
+            };
+        let reserve = budget::output_reserve(
+            window,
+            config.generation.max_tokens,
+            config.generation.thinking_budget,
+        );
+        let budget = budget::prompt_budget(window, reserve).saturating_sub(prompt_tokens);
+        if debug_mode {
+            eprintln!(
+                "context_window: {}/{} {} tokens, {} reserved for the answer, {} left for the terminal output",
+                config.provider, config.model, window, reserve, budget
+            );
+        }
+        pane_budget = pane_budget.min(budget);
+    }
+    Ok(pane_budget)
+}
+
+/// Provider with retries, and with the cache when it is enabled
+fn create_provider(
+    config: LLMConfig,
+    debug_mode: bool,
+    no_cache: bool,
+) -> Result<Box<dyn LLMProvider>, LLMError> {
+    let registry = Registry::builtin();
+    let retry_config = get_retry_config()?;
+    let with_retries = |provider| -> Box<dyn LLMProvider> {
+        Box::new(RetryProvider::new(
+            provider,
+            retry_config.clone(),
+            debug_mode,
+        ))
+    };
+    let provider = if config.provider == "fallback" {
+        // Members are retried one by one, so that a member that gave up is not asked again
+        let members = config
+            .fallback
+            .iter()
+            .map(|member| registry.create(member.clone()).map(with_retries))
+            .collect::<Result<Vec<_>, LLMError>>()?;
+        Box::new(FallbackProvider::with_providers(
+            members,
+            config.fallback_on.clone(),
+            config.debug,
+        )?)
+    } else {
+        with_retries(registry.create(config.clone())?)
+    };
+    // Cached answers are replayed without retrying or contacting the provider
+    Ok(if get_env_flag(ENV_CACHE) && !no_cache {
+        let cache = ResponseCache::new(get_cache_config()?);
+        Box::new(CacheProvider::new(provider, cache, &config, debug_mode))
+    } else {
+        Box::new(provider)
+    })
+}
+
+/// Split the paths given with `--image <path>` or `--image=<path>` off the words of the question
+fn split_image_args(user_input: &str) -> Result<(Vec<PathBuf>, Vec<&str>), LLMError> {
+    let mut paths = Vec::new();
+    let mut words = Vec::new();
+    let mut args = user_input.split_whitespace();
+    while let Some(arg) = args.next() {
+        if arg == ARG_IMAGE {
+            let path = args.next().ok_or_else(|| {
+                LLMError::ConfigError(format!("{} needs the path of an image", ARG_IMAGE))
+            })?;
+            paths.push(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix(&format!("{}=", ARG_IMAGE)) {
+            paths.push(PathBuf::from(path));
+        } else {
+            words.push(arg);
+        }
+    }
+    Ok((paths, words))
+}
+
+/// Refuse to ask a provider that would leave out the attached images
+fn check_image_support(provider: &dyn LLMProvider, images: &[Image]) -> Result<(), LLMError> {
+    if images.is_empty() || provider.supports_images() {
+        return Ok(());
+    }
+    Err(LLMError::ConfigError(format!(
+        "{}/{} cannot take images. Use openai, azure or anthropic with a vision model.",
+        provider.name(),
+        provider.model()
+    )))
+}
+
 /// Chat with LLM provider
 #[tokio::main]
 async fn chat(
     user_input: String,
+    images: Vec<Image>,
     system_message: String,
-    _debug_mode: &bool, // currently unused
-) -> Result<String, Box<dyn Error>> {
-    let config = get_llm_config().map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let provider = create_provider(config).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+    debug_mode: &bool,
+    structured_output: bool,
+    no_cache: bool,
+) -> Result<String, LLMError> {
+    let config = LLMConfig {
+        debug: *debug_mode,
+        ..get_llm_config()?
+    };
+    let allowed_tools = get_enabled_tools()?;
+    let max_tool_rounds = get_env_parsed::<u32>(ENV_MAX_TOOL_ROUNDS)?.unwrap_or(5);
+    let streaming = !get_env_flag(ENV_NO_STREAM);
+    let show_reasoning = !get_env_flag(ENV_HIDE_REASONING);
+    let provider = create_provider(config, *debug_mode, no_cache)?;
+    check_image_support(provider.as_ref(), &images)?;
+    if *debug_mode {
+        eprintln!("provider: {}", provider.name());
+        eprintln!("model: {}", provider.model());
+        let names: Vec<&str> = allowed_tools.iter().map(|t| t.name()).collect();
+        eprintln!("tools: {}", names.join(","));
+    }
+    if get_env_flag(ENV_CHECK_MODEL) {
+        check_model(&provider, *debug_mode).await;
+    }
 
-    let mut stream = LLMProvider::chat_stream(&provider, system_message, user_input)
-        .await
-        .map_err(|e| Box::new(e) as Box<dyn Error>)?;
+    let mut messages = vec![
+        Message::system(system_message),
+        Message::user(user_input).with_images(images),
+    ];
+    let started_at = Instant::now();
 
     let mut response_to_return = String::new();
-    while let Some(result) = stream.next().await {
-        match result {
-            Ok(content) => {
-                response_to_return.push_str(&content);
-                eprint!("{}", content);
+    let mut time_to_first_token = None;
+    let mut usage = Usage::default();
+    let mut tool_rounds = 0;
+    let answer = loop {
+        let answer = receive_answer(
+            &provider,
+            messages.clone(),
+            structured_output,
+            streaming,
+            show_reasoning,
+            started_at,
+            &mut time_to_first_token,
+        )
+        .await?;
+        // A structured answer is a single JSON document, written in the last round
+        if structured_output {
+            response_to_return.clear();
+        }
+        response_to_return.push_str(&answer.text);
+        usage.add(answer.usage);
+        if answer.tool_calls.is_empty() {
+            break answer;
+        }
+        if tool_rounds == max_tool_rounds {
+            eprintln!(
+                "\n\n*** Note: The model kept calling tools after {} rounds, so ask-sh stopped. Raise {} to allow more. ***",
+                max_tool_rounds, ENV_MAX_TOOL_ROUNDS
+            );
+            break answer;
+        }
+        tool_rounds += 1;
+
+        // end the text written before the calls so that they are shown on their own lines
+        if !answer.text.is_empty() || (show_reasoning && !answer.reasoning.text.is_empty()) {
+            eprintln!();
+        }
+        let results = run_tool_calls(&answer.tool_calls, &allowed_tools, *debug_mode).await;
+        messages.push(
+            Message::tool_calls(answer.text, answer.tool_calls).with_reasoning(answer.reasoning),
+        );
+        messages.extend(results);
+    };
+
+    if answer.stop_reason == Some(StopReason::MaxTokens) {
+        eprintln!("\n\n*** Note: The answer was cut off because it reached the maximum number of output tokens. ***");
+    }
+    if *debug_mode {
+        let (response_id, response_model) = (answer.id, answer.model);
+        eprintln!();
+        eprintln!("response_id: {}", response_id.unwrap_or_default());
+        eprintln!("response_model: {}", response_model.unwrap_or_default());
+        if let Some(elapsed) = time_to_first_token {
+            eprintln!("time_to_first_token: {:.2}s", elapsed.as_secs_f64());
+        }
+        eprintln!("total_time: {:.2}s", started_at.elapsed().as_secs_f64());
+        let count = |tokens: Option<u32>| tokens.map_or("unknown".to_string(), |t| t.to_string());
+        eprintln!("input_tokens: {}", count(usage.input_tokens));
+        eprintln!("output_tokens: {}", count(usage.output_tokens));
+        if usage.cache_read_tokens.is_some() || usage.cache_write_tokens.is_some() {
+            eprintln!("cache_read_tokens: {}", count(usage.cache_read_tokens));
+            eprintln!("cache_write_tokens: {}", count(usage.cache_write_tokens));
+        }
+        eprintln!("tool_rounds: {}", tool_rounds);
+        eprintln!("stop_reason: {:?}", answer.stop_reason);
+    }
+    Ok(response_to_return)
+}
+
+/// Ask every model of ASK_SH_COMPARE_PROVIDERS at once and show their answers, in sections
+/// or side by side. Returns the label and the answer of each model that answered.
+#[tokio::main]
+async fn compare(
+    user_input: String,
+    images: Vec<Image>,
+    system_message: String,
+    debug_mode: &bool,
+    structured_output: bool,
+    no_cache: bool,
+) -> Result<Vec<(String, String)>, LLMError> {
+    let pairs = get_compare_pairs();
+    if pairs.len() < 2 {
+        return Err(LLMError::ConfigError(format!(
+            "{} must list at least two providers to compare, e.g. anthropic,openai/gpt-4o-mini",
+            ENV_COMPARE_PROVIDERS
+        )));
+    }
+    let streaming = !get_env_flag(ENV_NO_STREAM);
+    let messages = vec![
+        Message::system(system_message),
+        Message::user(user_input).with_images(images.clone()),
+    ];
+
+    let mut receivers = Vec::new();
+    for pair in &pairs {
+        if pair.split('/').next() == Some("fallback") {
+            return Err(LLMError::ConfigError(
+                "Compare the members of a fallback chain directly".to_string(),
+            ));
+        }
+        // Tools are not offered, as their calls would run once per model
+        let config = LLMConfig {
+            debug: *debug_mode,
+            tools: Vec::new(),
+            ..get_provider_config(pair)?
+        };
+        let label = format!("{}/{}", config.provider, config.model);
+        let provider = create_provider(config, *debug_mode, no_cache)?;
+        check_image_support(provider.as_ref(), &images)?;
+        if *debug_mode {
+            eprintln!("compare: {}", label);
+        }
+
+        // All answers stream in at once. Those not shown yet wait in their channel.
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let messages = messages.clone();
+        tokio::spawn(async move {
+            let stream = if streaming {
+                provider.chat_stream(messages).await
+            } else {
+                provider
+                    .complete(messages)
+                    .await
+                    .map(Completion::into_stream)
+            };
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+            while let Some(event) = stream.next().await {
+                match event {
+                    Ok(StreamEvent::TextDelta(text)) => {
+                        let _ = sender.send(Ok(text));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                }
             }
-            Err(err) => {
-                eprint!("{}", err);
+        });
+        receivers.push((label, receiver));
+    }
+
+    let column_width =
+        compare::terminal_width().and_then(|width| compare::column_width(receivers.len(), width));
+    if column_width.is_some() {
+        eprintln!("Asking {} models...", receivers.len());
+    }
+    let mut answers = Vec::new();
+    let mut shown = Vec::new();
+    let mut first_error = None;
+    for (label, mut receiver) in receivers {
+        // Sections are printed as they stream in, columns once all answers are complete
+        let streamed = column_width.is_none();
+        if streamed {
+            eprintln!("\n{}\n", compare::section_header(&label));
+        }
+        let mut prose = structured_output.then(ProseExtractor::default);
+        let mut text = String::new();
+        let mut error = None;
+        while let Some(piece) = receiver.recv().await {
+            match piece {
+                Ok(piece) => {
+                    if streamed {
+                        match prose.as_mut() {
+                            Some(prose) => eprint!("{}", prose.push(&piece)),
+                            None => eprint!("{}", piece),
+                        }
+                    }
+                    text.push_str(&piece);
+                }
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        if streamed && !text.is_empty() {
+            eprintln!();
+        }
+        match error {
+            Some(e) => {
+                let message = format!("⚠️ {} failed: {}", label, e);
+                if streamed {
+                    eprintln!("{}", message);
+                }
+                shown.push((label, message));
+                first_error.get_or_insert(e);
+            }
+            None => {
+                let prose = if structured_output {
+                    suggestions::prose(&text)
+                } else {
+                    text.clone()
+                };
+                shown.push((label.clone(), prose));
+                answers.push((label, text));
             }
         }
     }
-    Ok(response_to_return)
+    if let Some(width) = column_width {
+        eprint!("\n{}", compare::side_by_side(&shown, width));
+    }
+
+    match first_error {
+        Some(e) if answers.is_empty() => Err(e),
+        _ => Ok(answers),
+    }
+}
+
+/// Configured models of a provider, one per member of a fallback chain
+fn configured_models(provider: &impl LLMProvider) -> Vec<&str> {
+    if provider.name() == "fallback" {
+        provider.model().split(", ").collect()
+    } else {
+        vec![provider.model()]
+    }
+}
+
+/// Warn when the configured model is not offered by the provider, e.g. because of a typo
+async fn check_model(provider: &impl LLMProvider, debug_mode: bool) {
+    match provider.list_models().await {
+        Ok(models) => {
+            for model in configured_models(provider) {
+                if !models.iter().any(|m| m.id == model) {
+                    eprintln!(
+                        "*** Note: {} does not list the model {}. Run `ask-sh {}` to see the available models. ***\n",
+                        provider.name(),
+                        model,
+                        ARG_MODELS
+                    );
+                }
+            }
+        }
+        Err(e) if debug_mode => eprintln!("model check skipped: {}", e),
+        Err(_) => {}
+    }
+}
+
+/// Remove every cached answer, for `ask-sh cache clear`
+fn clear_cache() -> Result<(), LLMError> {
+    let cache = ResponseCache::new(get_cache_config()?);
+    let removed = cache.clear().map_err(|e| {
+        LLMError::ConfigError(format!("Cannot clear {}: {}", cache.dir().display(), e))
+    })?;
+    println!(
+        "Removed {} cached answers from {}",
+        removed,
+        cache.dir().display()
+    );
+    Ok(())
+}
+
+/// Provider given to `models`: `[]`, `[--provider, <name>]` or `[--provider=<name>]`.
+/// None if the arguments are not of this form, e.g. for a question starting with "models".
+fn models_provider(args: &[String]) -> Option<Option<String>> {
+    match args {
+        [] => Some(None),
+        [flag, provider] if flag == ARG_PROVIDER => Some(Some(provider.clone())),
+        [arg] => arg
+            .strip_prefix(&format!("{}=", ARG_PROVIDER))
+            .map(|provider| Some(provider.to_string())),
+        _ => None,
+    }
+}
+
+/// Print the models of the configured provider, or of the one given with --provider
+#[tokio::main]
+async fn print_models(provider: Option<String>) -> Result<(), LLMError> {
+    dotenv().ok();
+    let provider = provider
+        .unwrap_or_else(|| env::var(ENV_LLM_PROVIDER).unwrap_or_else(|_| "openai".to_string()));
+    let provider = Registry::builtin().create(get_provider_config(&provider)?)?;
+    let models = provider.list_models().await?;
+    let configured = configured_models(&provider);
+    let width = models.iter().map(|m| m.id.len()).max().unwrap_or(0);
+    for model in &models {
+        // The configured models are marked with `*`
+        let marker = if configured.contains(&model.id.as_str()) {
+            '*'
+        } else {
+            ' '
+        };
+        let context = model
+            .context_length
+            .map_or(String::new(), |tokens| format!("{} tokens", tokens));
+        let line = format!("{} {:width$}  {}", marker, model.id, context, width = width);
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// Suggest how to fix an error, referring to the settings of the configured provider
+fn error_hint(error: &LLMError) -> Option<String> {
+    let provider = env::var(ENV_LLM_PROVIDER).unwrap_or_else(|_| "openai".to_string());
+    // Settings of the provider by what they are for
+    let registry = Registry::builtin();
+    let setting = |kind: SettingKind| {
+        let factory = registry.get(&provider)?;
+        factory
+            .settings()
+            .iter()
+            .find(|s| s.kind == kind)
+            .map(|s| s.key)
+    };
+    let api_key_var = setting(SettingKind::ApiKey);
+    let model_var = setting(SettingKind::Model);
+    let endpoint_var = setting(SettingKind::Endpoint);
+
+    match error {
+        LLMError::Authentication(_) => api_key_var.map(|var| format!("Check {}", var)),
+        LLMError::RateLimit { .. } => Some(format!(
+            "Wait a moment and try again, or raise {} to retry longer",
+            ENV_MAX_RETRIES
+        )),
+        LLMError::QuotaExhausted(_) => Some(format!(
+            "Check the plan and billing of your {} account",
+            provider
+        )),
+        LLMError::ModelNotFound(_) if provider == "ollama" => {
+            model_var.map(|var| format!("Check {} or download the model with `ollama pull`", var))
+        }
+        LLMError::ModelNotFound(_) => model_var.map(|var| {
+            format!(
+                "Check the model name in {} or list the models with `ask-sh {}`",
+                var, ARG_MODELS
+            )
+        }),
+        LLMError::ContextTooLong(_) => Some(format!(
+            "Set ASK_SH_{} to the model's context size, clear the terminal or run with {} to send less context",
+            CONTEXT_WINDOW, ARG_NO_PANE
+        )),
+        LLMError::Overloaded { .. } => Some(format!(
+            "The provider is busy; try again later or switch {}",
+            ENV_LLM_PROVIDER
+        )),
+        LLMError::Timeout(_) | LLMError::NetworkError(_) => Some(match endpoint_var {
+            Some(var) => format!("Check your network connection and {}", var),
+            None => "Check your network connection".to_string(),
+        }),
+        LLMError::StreamInterrupted(_) => Some("The answer is incomplete; ask again".to_string()),
+        _ => None,
+    }
+}
+
+/// Show the commands of a structured answer with what they do and how risky they are
+fn print_suggestions(suggestions: &[Suggestion]) {
+    if suggestions.is_empty() {
+        return;
+    }
+    eprintln!("\n");
+    for suggestion in suggestions {
+        eprintln!(
+            "$ {}  # {} ({})",
+            suggestion.command_line(),
+            suggestion.description,
+            suggestion.note()
+        );
+    }
+}
+
+/// Print the commands suggested by the compared models, each with the models suggesting it
+fn print_compared_suggestions(commands: &[(String, Vec<String>)]) {
+    if commands.is_empty() {
+        return;
+    }
+    eprintln!("\n");
+    for (command, labels) in commands {
+        eprintln!("$ {}  # {}", command, labels.join(", "));
+    }
+}
+
+/// Commands suggested by an answer
+fn extract_commands(response: &str, structured_output: bool, debug_mode: bool) -> Vec<String> {
+    if !structured_output {
+        return post_process(response);
+    }
+    match suggestions::parse(response) {
+        Some(answer) => {
+            let mut commands: Vec<String> = answer
+                .commands
+                .iter()
+                .map(Suggestion::command_line)
+                .collect();
+            let mut seen = std::collections::HashSet::new();
+            commands.retain(|command| !command.is_empty() && seen.insert(command.clone()));
+            commands
+        }
+        // The provider answered with plain text or the JSON was cut off
+        None => {
+            if debug_mode {
+                eprintln!("structured answer could not be parsed, extracting commands from text");
+            }
+            post_process(&suggestions::prose(response))
+        }
+    }
 }
 
 fn post_process(text: &str) -> Vec<String> {
@@ -256,6 +1127,34 @@ fn main() {
         return;
     }
 
+    // if called with models, list the models of the provider and exit.
+    // Other arguments after it make a question, e.g. `ask models of linux schedulers`.
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(provider) = args
+        .split_first()
+        .filter(|(first, _)| *first == ARG_MODELS)
+        .and_then(|(_, rest)| models_provider(rest))
+    {
+        if let Err(e) = print_models(provider) {
+            eprintln!("Listing models failed: {}", e);
+            if let Some(hint) = error_hint(&e) {
+                eprintln!("👉 {}", hint);
+            }
+            process::exit(e.exit_code());
+        }
+        return;
+    }
+
+    // if called with exactly `cache clear`, clear the cache of answers and exit.
+    // Other arguments after cache make a question, e.g. `ask cache invalidation strategies`.
+    if args == [ARG_CACHE, ARG_CACHE_CLEAR] {
+        if let Err(e) = clear_cache() {
+            eprintln!("Managing the cache failed: {}", e);
+            process::exit(e.exit_code());
+        }
+        return;
+    }
+
     // if called with only --version or -v, print version and exit
     if env::args().len() == 2 {
         let arg = env::args().nth(1).unwrap();
@@ -277,13 +1176,33 @@ fn main() {
         args.join(" ")
     };
 
-    // filter out predefined args
-    let user_input_without_flags = user_input
-        .split_whitespace()
+    // take out the images to attach, then filter out predefined args
+    let (image_paths, words) = match split_image_args(&user_input) {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
+    let user_input_without_flags = words
+        .into_iter()
         .filter(|arg| !ARG_STRINGS.contains(arg))
         .collect::<Vec<&str>>()
         .join(" ");
 
+    // read and check the images before anything is sent
+    let images = match image_paths
+        .iter()
+        .map(|path| Image::load(path))
+        .collect::<Result<Vec<Image>, LLMError>>()
+    {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Attaching the image failed: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
     // debug_mode is true if args contains --debug_ASK_SH or stdin text contains "--debug_ASK_SH" or env var ASK_SH_DEBUG is defined
     let debug_mode = env::args()
         .any(|arg| arg == ARG_DEBUG || user_input.contains(ARG_DEBUG) || get_env_flag(ENV_DEBUG));
@@ -299,6 +1218,16 @@ fn main() {
         || user_input.contains(ARG_NO_SUGGEST)
         || get_env_flag(ENV_NO_SUGGEST);
 
+    // no_cache is true if args contains --no_cache or stdin text contains "--no_cache"
+    let no_cache = env::args().any(|arg| arg == ARG_NO_CACHE) || user_input.contains(ARG_NO_CACHE);
+
+    // compare_mode is true if args contains --compare or stdin text contains "--compare"
+    let compare_mode =
+        env::args().any(|arg| arg == ARG_COMPARE) || user_input.contains(ARG_COMPARE);
+
+    // structured_output is true if env var ASK_SH_STRUCTURED_OUTPUT is defined
+    let structured_output = get_env_flag(ENV_STRUCTURED_OUTPUT);
+
     // run tmux capture-pane -p before anything is printed.
     // if run with no_pane, pane_text is empty string.
     // if run without no_pane, execute shell command tmux capture-pane -p, when the command fail, pane_text return empty string
@@ -378,18 +1307,58 @@ fn main() {
         eprintln!("is_using_stdin: {}", is_using_stdin);
         eprintln!("user_input: {}", user_input);
         eprintln!("user_input_without_flags: {}", user_input_without_flags);
+        let paths: Vec<String> = image_paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        eprintln!("images: {}", paths.join(","));
         eprintln!("debug_mode: {}", debug_mode);
         eprintln!("no_suggest: {}", no_suggest);
+        eprintln!("no_cache: {}", no_cache);
+        eprintln!("compare_mode: {}", compare_mode);
+        eprintln!("structured_output: {}", structured_output);
         eprintln!("pane_text: {}", pane_text);
     }
 
     let templates = prompts::get_template();
     let mut vars = std::collections::HashMap::new();
-    vars.insert("pane_text".to_owned(), pane_text.to_owned());
+    vars.insert("pane_text".to_owned(), String::new());
     vars.insert("user_input".to_owned(), user_input_without_flags.to_owned());
     vars.insert("user_os".to_owned(), user_info.os.to_owned());
     vars.insert("user_arch".to_owned(), user_info.arch.to_owned());
     vars.insert("user_shell".to_owned(), user_info.shell.to_owned());
+
+    // trim the terminal output to what fits in the context of the model besides the rest of the prompt and the answer
+    if send_pane {
+        let prompt_tokens =
+            budget::estimate_tokens(&templates.render("SYSTEM_PROMPT_WITH_PANE", &vars).unwrap())
+                + budget::estimate_tokens(
+                    &templates.render("USER_PROMPT_WITH_PANE", &vars).unwrap(),
+                )
+                + images.len() * budget::IMAGE_TOKENS;
+        // a configuration error is reported when asking
+        if let Ok(pane_budget) = get_pane_budget(compare_mode, prompt_tokens, debug_mode) {
+            let trimmed = budget::trim_lines(&pane_text, pane_budget);
+            if debug_mode && trimmed.dropped_lines > 0 {
+                eprintln!(
+                    "pane_text: dropped {} of {} lines (about {} tokens) to fit {} tokens, keeping {} older error lines",
+                    trimmed.dropped_lines,
+                    trimmed.kept_lines + trimmed.dropped_lines,
+                    trimmed.dropped_tokens,
+                    pane_budget,
+                    trimmed.kept_error_lines
+                );
+            }
+            if trimmed.kept_lines == 0 {
+                if debug_mode {
+                    eprintln!("no line of pane_text fits, so I set no_pane to true");
+                }
+                send_pane = false;
+            }
+            pane_text = trimmed.text;
+        }
+    }
+    vars.insert("pane_text".to_owned(), pane_text.to_owned());
     let system_message = if send_pane {
         templates.render("SYSTEM_PROMPT_WITH_PANE", &vars).unwrap()
     } else {
@@ -403,17 +1372,59 @@ fn main() {
         templates.render("USER_PROMPT_WITHOUT_PANE", &vars).unwrap()
     };
 
-    let response = chat(user_input, system_message, &debug_mode);
+    // Answers with the label of the model, which is only set when comparing
+    let answers = if compare_mode {
+        compare(
+            user_input,
+            images,
+            system_message,
+            &debug_mode,
+            structured_output,
+            no_cache,
+        )
+    } else {
+        chat(
+            user_input,
+            images,
+            system_message,
+            &debug_mode,
+            structured_output,
+            no_cache,
+        )
+        .map(|response| vec![(String::new(), response)])
+    };
 
-    let response = match response {
+    let answers = match answers {
         Ok(val) => val,
         Err(e) => {
             eprintln!("Communication with LLM provider failed: {}", e);
-            process::exit(1);
+            if let Some(hint) = error_hint(&e) {
+                eprintln!("👉 {}", hint);
+            }
+            process::exit(e.exit_code());
         }
     };
 
-    let commands = post_process(&response);
+    let commands = if compare_mode {
+        let suggested: Vec<(String, Vec<String>)> = answers
+            .into_iter()
+            .map(|(label, response)| {
+                let commands = extract_commands(&response, structured_output, debug_mode);
+                (label, commands)
+            })
+            .collect();
+        let merged = compare::merge_commands(&suggested);
+        print_compared_suggestions(&merged);
+        merged.into_iter().map(|(command, _)| command).collect()
+    } else {
+        let response = &answers[0].1;
+        if structured_output {
+            if let Some(answer) = suggestions::parse(response) {
+                print_suggestions(&answer.commands);
+            }
+        }
+        extract_commands(response, structured_output, debug_mode)
+    };
 
     // print suggested commands to stdout to further process
     if !no_suggest {
@@ -80,7 +80,7 @@ pub fn get_template() -> TinyTemplate<'static> {
 
     // Add templates from static PROMPTS
     for (name, content) in PROMPTS.iter() {
-        templates.add_template(name, &content).unwrap();
+        templates.add_template(name, content).unwrap();
     }
 
     templates
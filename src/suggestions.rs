@@ -0,0 +1,325 @@
+//! Structured answers: the prose for the user plus the commands it suggests, as JSON
+//!
+//! The JSON document streams in as text. `ProseExtractor` picks the prose out of it while
+//! it arrives, and `parse` reads the commands once it is complete.
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::llm::OutputSchema;
+
+/// Schema requested from the provider
+pub fn output_schema() -> OutputSchema {
+    OutputSchema {
+        name: "answer".to_string(),
+        // All properties are required and closed, as OpenAI's strict mode demands
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "answer": {
+                    "type": "string",
+                    "description": "Answer for the user, short and in Markdown. Refer to the commands by what they do instead of repeating them."
+                },
+                "commands": {
+                    "type": "array",
+                    "description": "Commands the user may want to run, most useful first.",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "command": {
+                                "type": "string",
+                                "description": "Command to type into the shell, on a single line. Chain several steps with && if needed."
+                            },
+                            "description": {
+                                "type": "string",
+                                "description": "What the command does, in a few words."
+                            },
+                            "risk": {
+                                "type": "string",
+                                "enum": ["low", "medium", "high"],
+                                "description": "low: only reads. medium: changes files or settings in a way that can be undone. high: deletes data or is hard to undo."
+                            },
+                            "needs_editing": {
+                                "type": "boolean",
+                                "description": "Whether the command contains placeholders the user must fill in before running it."
+                            }
+                        },
+                        "required": ["command", "description", "risk", "needs_editing"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["answer", "commands"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct StructuredAnswer {
+    pub answer: String,
+    pub commands: Vec<Suggestion>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub command: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub risk: Risk,
+    #[serde(default)]
+    pub needs_editing: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+impl Suggestion {
+    /// The command on one line, as the shell function reads one command per line
+    pub fn command_line(&self) -> String {
+        let mut line = String::new();
+        for part in self
+            .command
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+        {
+            if let Some(continued) = line.strip_suffix('\\') {
+                // A line continued with a backslash
+                line = format!("{} ", continued.trim_end());
+            } else if !line.is_empty() {
+                line.push_str("; ");
+            }
+            line.push_str(part);
+        }
+        line
+    }
+
+    /// Short note on the risk and editing, shown next to the command
+    pub fn note(&self) -> String {
+        let risk = match self.risk {
+            Risk::Low => "low risk",
+            Risk::Medium => "medium risk",
+            Risk::High => "⚠️ high risk",
+        };
+        if self.needs_editing {
+            format!("{}, edit before running", risk)
+        } else {
+            risk.to_string()
+        }
+    }
+}
+
+/// Parse a complete answer. None if it is not the requested JSON, e.g. when the provider
+/// does not support structured output and answered with plain text.
+pub fn parse(text: &str) -> Option<StructuredAnswer> {
+    serde_json::from_str(text.trim()).ok()
+}
+
+/// Prose of an answer, which is the whole text if it is not JSON
+pub fn prose(text: &str) -> String {
+    let mut extractor = ProseExtractor::default();
+    extractor.push(text)
+}
+
+/// Picks the `answer` string out of a JSON document received in pieces
+#[derive(Default)]
+pub struct ProseExtractor {
+    plain: Option<bool>, // Decided by the first character: JSON starts with `{`
+    depth: usize,
+    in_string: bool,
+    in_key: bool,           // The current string is a key of the top-level object
+    expecting_key: bool,    // The next string at the top level is a key
+    key: String,            // Last key of the top-level object
+    answer_next: bool,      // The next value at the top level is the answer
+    in_answer: bool,        // The current string is the answer
+    escape: Option<String>, // Escape sequence read so far, without the backslash
+    high_surrogate: Option<u32>,
+}
+
+impl ProseExtractor {
+    /// Feed the next piece of the document, returning the new prose
+    pub fn push(&mut self, piece: &str) -> String {
+        let mut prose = String::new();
+        for c in piece.chars() {
+            match self.plain {
+                Some(true) => prose.push(c),
+                Some(false) => self.read(c, &mut prose),
+                None if c.is_whitespace() => {}
+                None => {
+                    self.plain = Some(c != '{');
+                    if c == '{' {
+                        self.read(c, &mut prose);
+                    } else {
+                        prose.push(c);
+                    }
+                }
+            }
+        }
+        prose
+    }
+
+    fn read(&mut self, c: char, prose: &mut String) {
+        if !self.in_string {
+            match c {
+                '"' => {
+                    self.in_string = true;
+                    self.in_key = self.depth == 1 && self.expecting_key;
+                    self.in_answer = self.depth == 1 && !self.in_key && self.answer_next;
+                    if self.in_key {
+                        self.key.clear();
+                    }
+                }
+                '{' | '[' => {
+                    self.depth += 1;
+                    self.expecting_key = self.depth == 1 && c == '{';
+                    self.answer_next = false;
+                }
+                '}' | ']' => self.depth = self.depth.saturating_sub(1),
+                ',' if self.depth == 1 => self.expecting_key = true,
+                ':' if self.depth == 1 => {
+                    self.expecting_key = false;
+                    self.answer_next = self.key == "answer";
+                }
+                c if c.is_whitespace() => {}
+                _ => self.answer_next = false, // A number, boolean or null
+            }
+            return;
+        }
+
+        let decoded = match self.escape.as_mut() {
+            Some(escape) => {
+                escape.push(c);
+                match Self::decode_escape(escape) {
+                    Some(code) => {
+                        self.escape = None;
+                        self.combine_surrogates(code)
+                    }
+                    None => return, // Incomplete
+                }
+            }
+            None if c == '\\' => {
+                self.escape = Some(String::new());
+                return;
+            }
+            None if c == '"' => {
+                self.in_string = false;
+                if !self.in_key && self.depth == 1 {
+                    self.answer_next = false;
+                }
+                self.in_answer = false;
+                return;
+            }
+            None => Some(c),
+        };
+
+        if let Some(decoded) = decoded {
+            if self.in_key {
+                self.key.push(decoded);
+            } else if self.in_answer {
+                prose.push(decoded);
+            }
+        }
+    }
+
+    /// Code point of a complete escape sequence
+    fn decode_escape(escape: &str) -> Option<u32> {
+        let mut chars = escape.chars();
+        let code = match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'u' => {
+                let hex = chars.as_str();
+                return if hex.len() == 4 {
+                    Some(u32::from_str_radix(hex, 16).unwrap_or(0xfffd))
+                } else {
+                    None
+                };
+            }
+            other => other, // `"`, `\` and `/`
+        };
+        Some(code as u32)
+    }
+
+    /// Join the halves of a character outside the Basic Multilingual Plane
+    fn combine_surrogates(&mut self, code: u32) -> Option<char> {
+        match (self.high_surrogate.take(), code) {
+            (None, 0xd800..=0xdbff) => {
+                self.high_surrogate = Some(code);
+                None
+            }
+            (Some(high), 0xdc00..=0xdfff) => {
+                char::from_u32(0x10000 + ((high - 0xd800) << 10) + (code - 0xdc00))
+            }
+            (_, code) => Some(char::from_u32(code).unwrap_or('\u{fffd}')),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER: &str = r#"{"answer": "Use \"du\":\n- sorted é 😀", "commands": [{"command": "du -sh * | sort -h", "description": "Show sizes", "risk": "low", "needs_editing": false}, {"command": "rm -rf <dir>", "description": "Delete", "risk": "high", "needs_editing": true}]}"#;
+
+    #[test]
+    fn test_prose_is_extracted_from_pieces() {
+        let expected = "Use \"du\":\n- sorted é 😀";
+        for size in [1, 2, 3, 7, ANSWER.len()] {
+            let mut extractor = ProseExtractor::default();
+            let chars: Vec<char> = ANSWER.chars().collect();
+            let prose: String = chars
+                .chunks(size)
+                .map(|piece| extractor.push(&piece.iter().collect::<String>()))
+                .collect();
+            assert_eq!(prose, expected, "pieces of {} chars", size);
+        }
+    }
+
+    #[test]
+    fn test_prose_ignores_nested_answer_keys() {
+        let text = r#"{"commands": [{"answer": "no"}], "note": "answer", "answer": "yes"}"#;
+        assert_eq!(prose(text), "yes");
+    }
+
+    #[test]
+    fn test_plain_text_passes_through() {
+        let text = "Run ```ls -la```";
+        assert_eq!(prose(text), text);
+        assert_eq!(parse(text), None);
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let answer = parse(ANSWER).unwrap();
+        assert_eq!(answer.commands.len(), 2);
+        assert_eq!(answer.commands[0].command_line(), "du -sh * | sort -h");
+        assert_eq!(answer.commands[0].note(), "low risk");
+        assert_eq!(answer.commands[1].risk, Risk::High);
+        assert_eq!(
+            answer.commands[1].note(),
+            "⚠️ high risk, edit before running"
+        );
+    }
+
+    #[test]
+    fn test_multi_line_commands_become_one_line() {
+        let suggestion = Suggestion {
+            command: "cd build\nmake \\\n  -j8\n".to_string(),
+            description: String::new(),
+            risk: Risk::Low,
+            needs_editing: false,
+        };
+        assert_eq!(suggestion.command_line(), "cd build; make -j8");
+    }
+}
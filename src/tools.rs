@@ -0,0 +1,447 @@
+//! Read-only tools the model may call to gather context
+//!
+//! Every tool only reads: files and directories under the current directory, the manual
+//! page of a command, or the tmux scrollback. Which tools are offered is configured by the
+//! user. Hidden files and files that usually hold secrets are never read.
+
+use serde_json::{json, Value};
+use std::{
+    env,
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::process::Command;
+
+use crate::llm::{ToolCall, ToolSpec};
+
+const MAX_OUTPUT_BYTES: usize = 16 * 1024; // Longer outputs are cut to save tokens
+const HELP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Names of files holding keys or credentials, besides hidden files like .env or .ssh/
+const SECRET_NAMES: &[&str] = &[
+    "id_rsa",
+    "id_dsa",
+    "id_ecdsa",
+    "id_ed25519",
+    "credentials",
+    "credentials.json",
+];
+const SECRET_EXTENSIONS: &[&str] = &["pem", "key", "p12", "pfx", "jks", "keystore"];
+const DEFAULT_SCROLLBACK_LINES: u64 = 200;
+const MAX_SCROLLBACK_LINES: u64 = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinTool {
+    ReadFile,
+    ListDirectory,
+    CommandHelp,
+    TmuxScrollback,
+}
+
+pub const ALL_TOOLS: &[BuiltinTool] = &[
+    BuiltinTool::ReadFile,
+    BuiltinTool::ListDirectory,
+    BuiltinTool::CommandHelp,
+    BuiltinTool::TmuxScrollback,
+];
+
+impl BuiltinTool {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ALL_TOOLS.iter().copied().find(|tool| tool.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinTool::ReadFile => "read_file",
+            BuiltinTool::ListDirectory => "list_directory",
+            BuiltinTool::CommandHelp => "command_help",
+            BuiltinTool::TmuxScrollback => "tmux_scrollback",
+        }
+    }
+
+    /// Definition sent to the model
+    pub fn spec(&self) -> ToolSpec {
+        let (description, parameters) = match self {
+            BuiltinTool::ReadFile => (
+                "Read a text file in the user's current directory or below it.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "Path relative to the current directory"}
+                    },
+                    "required": ["path"]
+                }),
+            ),
+            BuiltinTool::ListDirectory => (
+                "List the entries of a directory in the user's current directory or below it. Directories end with `/`.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "Path relative to the current directory (default: `.`)"}
+                    }
+                }),
+            ),
+            BuiltinTool::CommandHelp => (
+                "Show the manual page (`man <command>`) of a command installed on the user's machine.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {"type": "string", "description": "Name of the command, e.g. `tar`"}
+                    },
+                    "required": ["command"]
+                }),
+            ),
+            BuiltinTool::TmuxScrollback => (
+                "Read more lines of the user's terminal from the tmux scrollback, ending with the visible screen.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "lines": {"type": "integer", "description": "Number of lines above the visible screen (default: 200, at most 2000)"}
+                    }
+                }),
+            ),
+        };
+        ToolSpec {
+            name: self.name().to_string(),
+            description: description.to_string(),
+            parameters,
+        }
+    }
+
+    /// Run the tool. Failures are returned as text for the model to read.
+    pub async fn run(&self, call: &ToolCall) -> Result<String, String> {
+        let arguments = call
+            .parsed_arguments()
+            .map_err(|e| format!("Invalid arguments: {}", e))?;
+        let string_argument = |name: &str| arguments.get(name).and_then(Value::as_str);
+
+        let output = match self {
+            BuiltinTool::ReadFile => {
+                let path = string_argument("path").ok_or("Missing argument: path")?;
+                read_file(path)?
+            }
+            BuiltinTool::ListDirectory => {
+                let path = string_argument("path").unwrap_or(".");
+                list_directory(&resolve_in_cwd(path)?)?
+            }
+            BuiltinTool::CommandHelp => {
+                let command = string_argument("command").ok_or("Missing argument: command")?;
+                command_help(command).await?
+            }
+            BuiltinTool::TmuxScrollback => {
+                let lines = arguments
+                    .get("lines")
+                    .and_then(Value::as_u64)
+                    .unwrap_or(DEFAULT_SCROLLBACK_LINES)
+                    .min(MAX_SCROLLBACK_LINES);
+                tmux_scrollback(lines).await?
+            }
+        };
+        Ok(truncate(output))
+    }
+}
+
+/// Resolve a path, refusing anything outside the current directory (including through
+/// symlinks) and hidden files and directories
+fn resolve_in_cwd(path: &str) -> Result<PathBuf, String> {
+    let cwd = env::current_dir()
+        .and_then(|cwd| cwd.canonicalize())
+        .map_err(|e| e.to_string())?;
+    let resolved = cwd
+        .join(path)
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let Ok(relative) = resolved.strip_prefix(&cwd) else {
+        return Err(format!("{} is outside the current directory", path));
+    };
+    let hidden = relative
+        .components()
+        .any(|c| matches!(c, Component::Normal(name) if name.to_string_lossy().starts_with('.')));
+    if hidden {
+        return Err(format!("{} is hidden and may hold secrets", path));
+    }
+    Ok(resolved)
+}
+
+/// Read the beginning of a regular file, refusing those that usually hold secrets
+fn read_file(path: &str) -> Result<String, String> {
+    let resolved = resolve_in_cwd(path)?;
+    let name = resolved
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let extension = resolved
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if SECRET_NAMES.contains(&name.as_str()) || SECRET_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(format!("{} may hold secrets", path));
+    }
+    // Reading a FIFO or a device could block forever
+    let metadata = fs::metadata(&resolved).map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a regular file", path));
+    }
+
+    // One byte more than is shown, so that truncation is noted
+    let mut bytes = Vec::new();
+    File::open(&resolved)
+        .and_then(|file| {
+            file.take(MAX_OUTPUT_BYTES as u64 + 1)
+                .read_to_end(&mut bytes)
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn list_directory(path: &Path) -> Result<String, String> {
+    let mut entries = fs::read_dir(path)
+        .map_err(|e| e.to_string())?
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => format!("{}/", name),
+                _ => name,
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+    Ok(entries.join("\n"))
+}
+
+async fn command_help(command: &str) -> Result<String, String> {
+    // Only a bare command name, so that the model cannot run anything else
+    let is_name = !command.is_empty()
+        && !command.starts_with('-')
+        && command
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'));
+    if !is_name {
+        return Err(format!("Not a command name: {}", command));
+    }
+
+    // The command itself is never run, as nothing guarantees that its --help only reads
+    let child = Command::new("man")
+        .arg(command)
+        .env("MANPAGER", "cat")
+        .env("MANWIDTH", "100")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(HELP_TIMEOUT, child)
+        .await
+        .map_err(|_| format!("`man {}` did not finish in time", command))?
+        .map_err(|e| format!("Cannot run man: {}", e))?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "No manual page for {}: {}",
+            command,
+            message.trim()
+        ));
+    }
+    Ok(strip_overstrike(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Remove the bold and underline of a formatted manual page, written as `c\x08c` and `_\x08c`
+fn strip_overstrike(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\x08' {
+            output.pop();
+        } else {
+            output.push(c);
+        }
+    }
+    output
+}
+
+async fn tmux_scrollback(lines: u64) -> Result<String, String> {
+    if env::var("TMUX").is_err() {
+        return Err("The terminal is not running inside tmux".to_string());
+    }
+    let output = Command::new("tmux")
+        .arg("capture-pane")
+        .arg("-p")
+        .arg("-S")
+        .arg(format!("-{}", lines))
+        .output()
+        .await
+        .map_err(|e| format!("tmux capture-pane failed: {}", e))?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_string())
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() > MAX_OUTPUT_BYTES {
+        let mut end = MAX_OUTPUT_BYTES;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n[output truncated]");
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn call(tool: BuiltinTool, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            name: tool.name().to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tool_names_round_trip() {
+        for tool in ALL_TOOLS {
+            assert_eq!(BuiltinTool::from_name(tool.name()), Some(*tool));
+            assert_eq!(tool.spec().parameters["type"], "object");
+        }
+        assert_eq!(BuiltinTool::from_name("run_shell"), None);
+    }
+
+    #[tokio::test]
+    async fn test_files_outside_cwd_are_refused() {
+        // Tests run in the crate root
+        let output = BuiltinTool::ReadFile
+            .run(&call(BuiltinTool::ReadFile, r#"{"path": "Cargo.toml"}"#))
+            .await
+            .unwrap();
+        assert!(output.contains("[package]"));
+
+        let dir = TempDir::new().unwrap();
+        let outside = dir.path().canonicalize().unwrap().join("notes.txt");
+        fs::write(&outside, "outside").unwrap();
+        // The same file reached with `..` from the current directory
+        let depth = env::current_dir()
+            .unwrap()
+            .canonicalize()
+            .unwrap()
+            .components()
+            .count()
+            - 1;
+        let relative = format!(
+            "{}{}",
+            "../".repeat(depth),
+            outside.strip_prefix("/").unwrap().display()
+        );
+        for path in [outside.display().to_string(), relative] {
+            let arguments = json!({ "path": path }).to_string();
+            let err = BuiltinTool::ReadFile
+                .run(&call(BuiltinTool::ReadFile, &arguments))
+                .await
+                .unwrap_err();
+            assert_eq!(err, format!("{} is outside the current directory", path));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hidden_secret_and_special_files_are_refused() {
+        let err = BuiltinTool::ReadFile
+            .run(&call(BuiltinTool::ReadFile, r#"{"path": ".git/HEAD"}"#))
+            .await
+            .unwrap_err();
+        assert_eq!(err, ".git/HEAD is hidden and may hold secrets");
+        let err = BuiltinTool::ListDirectory
+            .run(&call(BuiltinTool::ListDirectory, r#"{"path": ".git"}"#))
+            .await
+            .unwrap_err();
+        assert_eq!(err, ".git is hidden and may hold secrets");
+
+        // Under the current directory, in the ignored build directory
+        let dir = tempfile::Builder::new()
+            .prefix("ask-sh-tools-")
+            .tempdir_in("target")
+            .unwrap();
+        let base = dir
+            .path()
+            .strip_prefix(env::current_dir().unwrap())
+            .unwrap_or(dir.path());
+        for name in ["id_ed25519", "server.pem"] {
+            fs::write(dir.path().join(name), "secret").unwrap();
+            let path = base.join(name).display().to_string();
+            let arguments = json!({ "path": path }).to_string();
+            let err = BuiltinTool::ReadFile
+                .run(&call(BuiltinTool::ReadFile, &arguments))
+                .await
+                .unwrap_err();
+            assert_eq!(err, format!("{} may hold secrets", path));
+        }
+
+        let fifo = dir.path().join("pipe");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+        let path = base.join("pipe").display().to_string();
+        let arguments = json!({ "path": path }).to_string();
+        let err = BuiltinTool::ReadFile
+            .run(&call(BuiltinTool::ReadFile, &arguments))
+            .await
+            .unwrap_err();
+        assert_eq!(err, format!("{} is not a regular file", path));
+
+        // Large files are only read up to the output limit
+        fs::write(
+            dir.path().join("big.log"),
+            "x".repeat(10 * MAX_OUTPUT_BYTES),
+        )
+        .unwrap();
+        let path = base.join("big.log").display().to_string();
+        let arguments = json!({ "path": path }).to_string();
+        let output = BuiltinTool::ReadFile
+            .run(&call(BuiltinTool::ReadFile, &arguments))
+            .await
+            .unwrap();
+        assert!(output.ends_with("[output truncated]"));
+    }
+
+    #[tokio::test]
+    async fn test_list_directory_marks_directories() {
+        let output = BuiltinTool::ListDirectory
+            .run(&call(BuiltinTool::ListDirectory, ""))
+            .await
+            .unwrap();
+        let entries: Vec<&str> = output.lines().collect();
+        assert!(entries.contains(&"src/"));
+        assert!(entries.contains(&"Cargo.toml"));
+    }
+
+    #[tokio::test]
+    async fn test_command_help_only_takes_a_name() {
+        for command in ["ls; rm -rf /", "../bin/ls", "--version", ""] {
+            let arguments = json!({ "command": command }).to_string();
+            let err = BuiltinTool::CommandHelp
+                .run(&call(BuiltinTool::CommandHelp, &arguments))
+                .await
+                .unwrap_err();
+            assert!(err.starts_with("Not a command name"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_overstrike_is_stripped() {
+        assert_eq!(
+            strip_overstrike("N\x08NA\x08AM\x08ME\x08E _\x08l_\x08s"),
+            "NAME ls"
+        );
+    }
+
+    #[test]
+    fn test_long_output_is_truncated() {
+        let output = truncate("é".repeat(MAX_OUTPUT_BYTES));
+        assert!(output.ends_with("[output truncated]"));
+        assert!(output.len() < MAX_OUTPUT_BYTES + 32);
+    }
+}